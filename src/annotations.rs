@@ -0,0 +1,638 @@
+use std::{collections::BTreeMap, time::Duration};
+
+use k8s_openapi::chrono::{DateTime, Utc};
+
+use crate::error::Error;
+
+pub const PREFIX: &str = "frp-operator.io/";
+pub const BANDWIDTH_LIMIT: &str = "frp-operator.io/bandwidth-limit";
+pub const BANDWIDTH_LIMIT_MODE: &str = "frp-operator.io/bandwidth-limit-mode";
+pub const PROXY_PROTOCOL: &str = "frp-operator.io/proxy-protocol";
+pub const BACKEND_PROTOCOL: &str = "frp-operator.io/backend-protocol";
+pub const SUBDOMAIN: &str = "frp-operator.io/subdomain";
+pub const CANARY: &str = "frp-operator.io/canary";
+pub const CANARY_WEIGHT: &str = "frp-operator.io/canary-weight";
+pub const STATUS: &str = "frp-operator.io/status";
+pub const REMOTE_PORT: &str = "frp-operator.io/remote-port";
+pub const PORT_MAP: &str = "frp-operator.io/port-map";
+pub const LOCAL_PORT_MODE: &str = "frp-operator.io/local-port-mode";
+pub const EXPOSE_PORTS: &str = "frp-operator.io/expose-ports";
+pub const STCP_SECRET_REF: &str = "frp-operator.io/stcp-secret-ref";
+pub const SUDP_SECRET_REF: &str = "frp-operator.io/sudp-secret-ref";
+pub const VISITORS: &str = "frp-operator.io/visitors";
+pub const ALLOCATED_PORTS: &str = "frp-operator.io/allocated-ports";
+pub const HOSTNAME: &str = "frp-operator.io/hostname";
+pub const HTTP_DOMAINS: &str = "frp-operator.io/http-domains";
+pub const PROXY_NAME_TEMPLATE: &str = "frp-operator.io/proxy-name-template";
+pub const PROXY_SNIPPET: &str = "frp-operator.io/proxy-snippet";
+pub const EXPIRE_AFTER: &str = "frp-operator.io/expire-after";
+pub const EXPIRES_AT: &str = "frp-operator.io/expires-at";
+pub const EXPOSE: &str = "frp-operator.io/expose";
+pub const LOAD_BALANCER: &str = "frp-operator.io/load-balancer";
+pub const LB_GROUP: &str = "frp-operator.io/lb-group";
+pub const LB_GROUP_KEY: &str = "frp-operator.io/lb-group-key";
+pub const HEALTH_CHECK: &str = "frp-operator.io/health-check";
+pub const HEALTH_CHECK_TIMEOUT: &str = "frp-operator.io/health-check-timeout";
+pub const HEALTH_CHECK_INTERVAL: &str = "frp-operator.io/health-check-interval";
+pub const HEALTH_CHECK_MAX_FAILED: &str = "frp-operator.io/health-check-max-failed";
+
+/// Annotations written by the operator rather than the owner of the object.
+pub const OPERATOR_OWNED: [&str; 4] = [STATUS, VISITORS, ALLOCATED_PORTS, EXPIRES_AT];
+
+#[derive(Default, Debug, Clone, Copy, PartialEq)]
+pub enum BackendProtocol {
+    #[default]
+    Http,
+    Https,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LocalPortMode {
+    Service,
+    Target,
+    Endpoints,
+}
+
+fn invalid(key: &str, reason: impl Into<String>) -> Error {
+    Error::InvalidAnnotation(key.to_string(), reason.into())
+}
+
+/// Parses a bandwidth quantity in frp's syntax, e.g. `10MB` or `512KB`.
+pub fn bandwidth_limit(annotations: &BTreeMap<String, String>) -> Result<Option<String>, Error> {
+    let Some(value) = annotations.get(BANDWIDTH_LIMIT) else {
+        return Ok(None);
+    };
+
+    let number = value
+        .strip_suffix("MB")
+        .or_else(|| value.strip_suffix("KB"))
+        .ok_or_else(|| invalid(BANDWIDTH_LIMIT, format!("{value} must end with MB or KB")))?;
+
+    match number.parse::<u64>() {
+        Ok(n) if n > 0 => Ok(Some(value.to_owned())),
+        _ => Err(invalid(
+            BANDWIDTH_LIMIT,
+            format!("{value} must be a positive integer followed by MB or KB"),
+        )),
+    }
+}
+
+pub fn bandwidth_limit_mode(
+    annotations: &BTreeMap<String, String>,
+) -> Result<Option<String>, Error> {
+    match annotations.get(BANDWIDTH_LIMIT_MODE).map(String::as_str) {
+        None => Ok(None),
+        Some(mode @ ("client" | "server")) => Ok(Some(mode.to_string())),
+        Some(mode) => Err(invalid(
+            BANDWIDTH_LIMIT_MODE,
+            format!("unknown mode {mode}, expected client or server"),
+        )),
+    }
+}
+
+pub fn proxy_protocol(annotations: &BTreeMap<String, String>) -> Result<Option<String>, Error> {
+    match annotations.get(PROXY_PROTOCOL).map(String::as_str) {
+        None => Ok(None),
+        Some(version @ ("v1" | "v2")) => Ok(Some(version.to_string())),
+        Some(version) => Err(invalid(
+            PROXY_PROTOCOL,
+            format!("unknown version {version}, expected v1 or v2"),
+        )),
+    }
+}
+
+pub fn backend_protocol(annotations: &BTreeMap<String, String>) -> Result<BackendProtocol, Error> {
+    match annotations.get(BACKEND_PROTOCOL).map(|p| p.to_uppercase()) {
+        None => Ok(BackendProtocol::default()),
+        Some(p) if p == "HTTP" => Ok(BackendProtocol::Http),
+        Some(p) if p == "HTTPS" => Ok(BackendProtocol::Https),
+        Some(p) => Err(invalid(
+            BACKEND_PROTOCOL,
+            format!("unknown protocol {p}, expected HTTP or HTTPS"),
+        )),
+    }
+}
+
+pub fn subdomain(annotations: &BTreeMap<String, String>) -> Result<Option<String>, Error> {
+    let Some(value) = annotations.get(SUBDOMAIN) else {
+        return Ok(None);
+    };
+
+    if value.is_empty()
+        || !value
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+    {
+        return Err(invalid(
+            SUBDOMAIN,
+            format!("{value} must be a single lowercase DNS label"),
+        ));
+    }
+
+    Ok(Some(value.to_owned()))
+}
+
+/// Returns the traffic weight (0-100) when the resource is marked as canary.
+pub fn canary_weight(annotations: &BTreeMap<String, String>) -> Result<Option<u8>, Error> {
+    match annotations.get(CANARY).map(String::as_str) {
+        None | Some("false") => return Ok(None),
+        Some("true") => {}
+        Some(value) => return Err(invalid(CANARY, format!("{value} must be true or false"))),
+    }
+
+    match annotations.get(CANARY_WEIGHT) {
+        None => Ok(Some(0)),
+        Some(value) => match value.parse::<u8>() {
+            Ok(weight) if weight <= 100 => Ok(Some(weight)),
+            _ => Err(invalid(
+                CANARY_WEIGHT,
+                format!("{value} must be an integer between 0 and 100"),
+            )),
+        },
+    }
+}
+
+fn parse_port(key: &str, value: &str) -> Result<u16, Error> {
+    match value.parse::<u16>() {
+        Ok(port) if port > 0 => Ok(port),
+        _ => Err(invalid(key, format!("{value} is not a valid port"))),
+    }
+}
+
+/// Parses `port-map`, either `<port>:<remote port>,...` or a JSON object, into
+/// remote ports keyed by service port name or number.
+pub fn port_map(annotations: &BTreeMap<String, String>) -> Result<BTreeMap<String, u16>, Error> {
+    let Some(value) = annotations.get(PORT_MAP) else {
+        return Ok(BTreeMap::new());
+    };
+
+    if value.trim_start().starts_with('{') {
+        let map: BTreeMap<String, serde_json::Value> = serde_json::from_str(value)
+            .map_err(|err| invalid(PORT_MAP, format!("invalid JSON: {err}")))?;
+
+        return map
+            .into_iter()
+            .map(|(port, remote_port)| {
+                let remote_port = match remote_port {
+                    serde_json::Value::String(remote_port) => remote_port,
+                    remote_port => remote_port.to_string(),
+                };
+                Ok((port, parse_port(PORT_MAP, &remote_port)?))
+            })
+            .collect();
+    }
+
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| {
+            let (port, remote_port) = entry.split_once(':').ok_or_else(|| {
+                invalid(PORT_MAP, format!("{entry} must be <port>:<remote port>"))
+            })?;
+            Ok((
+                port.trim().to_string(),
+                parse_port(PORT_MAP, remote_port.trim())?,
+            ))
+        })
+        .collect()
+}
+
+/// Resolves the remote port for a service port from `remote-port-<name>`,
+/// `remote-port-<number>-<protocol>`, `remote-port-<number>` or `port-map`,
+/// falling back to `remote-port` which is only allowed on single-port Services.
+pub fn remote_port(
+    annotations: &BTreeMap<String, String>,
+    port_name: Option<&str>,
+    port: i32,
+    protocol: &str,
+    single_port: bool,
+) -> Result<Option<u16>, Error> {
+    for key in port_name
+        .into_iter()
+        .map(str::to_string)
+        .chain([
+            format!("{port}-{}", protocol.to_lowercase()),
+            port.to_string(),
+        ])
+        .map(|suffix| format!("{REMOTE_PORT}-{suffix}"))
+    {
+        if let Some(value) = annotations.get(&key) {
+            return parse_port(&key, value).map(Some);
+        }
+    }
+
+    let port_map = port_map(annotations)?;
+    if let Some(remote_port) = port_name
+        .and_then(|name| port_map.get(name))
+        .or_else(|| port_map.get(&port.to_string()))
+    {
+        return Ok(Some(*remote_port));
+    }
+
+    match annotations.get(REMOTE_PORT) {
+        None => Ok(None),
+        Some(_) if !single_port => Err(invalid(
+            REMOTE_PORT,
+            "only allowed on single-port services, use remote-port-<port name> instead",
+        )),
+        Some(value) => parse_port(REMOTE_PORT, value).map(Some),
+    }
+}
+
+pub fn local_port_mode(
+    annotations: &BTreeMap<String, String>,
+) -> Result<Option<LocalPortMode>, Error> {
+    match annotations.get(LOCAL_PORT_MODE).map(String::as_str) {
+        None => Ok(None),
+        Some("service") => Ok(Some(LocalPortMode::Service)),
+        Some("target") => Ok(Some(LocalPortMode::Target)),
+        Some("endpoints") => Ok(Some(LocalPortMode::Endpoints)),
+        Some(value) => Err(invalid(
+            LOCAL_PORT_MODE,
+            format!("unknown mode {value}, expected service, target or endpoints"),
+        )),
+    }
+}
+
+/// Returns the port names or numbers listed in `expose-ports`, if set.
+pub fn expose_ports(annotations: &BTreeMap<String, String>) -> Option<Vec<String>> {
+    annotations.get(EXPOSE_PORTS).map(|value| {
+        value
+            .split(',')
+            .map(str::trim)
+            .filter(|port| !port.is_empty())
+            .map(str::to_string)
+            .collect()
+    })
+}
+
+/// Parses a `<secret name>[/<key>]` reference such as `stcp-secret-ref`, the key defaults to `sk`.
+pub fn secret_ref(
+    annotations: &BTreeMap<String, String>,
+    annotation: &str,
+) -> Result<Option<(String, String)>, Error> {
+    let Some(value) = annotations.get(annotation) else {
+        return Ok(None);
+    };
+
+    match value.split_once('/') {
+        None if !value.is_empty() => Ok(Some((value.to_owned(), "sk".to_string()))),
+        Some((name, key)) if !name.is_empty() && !key.is_empty() => {
+            Ok(Some((name.to_owned(), key.to_owned())))
+        }
+        _ => Err(invalid(
+            annotation,
+            format!("{value} must be <secret name> or <secret name>/<key>"),
+        )),
+    }
+}
+
+fn is_dns_name(value: &str) -> bool {
+    let valid_label = |label: &str| {
+        !label.is_empty()
+            && !label.starts_with('-')
+            && !label.ends_with('-')
+            && label
+                .chars()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+    };
+
+    value.len() <= 253 && value.split('.').all(valid_label)
+}
+
+/// Returns the DNS name to publish in the load balancer status, if set.
+pub fn hostname(annotations: &BTreeMap<String, String>) -> Result<Option<String>, Error> {
+    let Some(value) = annotations.get(HOSTNAME) else {
+        return Ok(None);
+    };
+
+    if !is_dns_name(value) {
+        return Err(invalid(
+            HOSTNAME,
+            format!("{value} must be a lowercase DNS name"),
+        ));
+    }
+
+    Ok(Some(value.to_owned()))
+}
+
+pub fn health_check(annotations: &BTreeMap<String, String>) -> Result<Option<String>, Error> {
+    match annotations.get(HEALTH_CHECK).map(String::as_str) {
+        None => Ok(None),
+        Some("tcp") => Ok(Some("tcp".to_string())),
+        Some(value) => Err(invalid(
+            HEALTH_CHECK,
+            format!("unknown health check {value}, expected tcp"),
+        )),
+    }
+}
+
+/// Parses a positive integer annotation, such as the health check timeout.
+pub fn positive_integer(
+    annotations: &BTreeMap<String, String>,
+    key: &str,
+) -> Result<Option<u32>, Error> {
+    let Some(value) = annotations.get(key) else {
+        return Ok(None);
+    };
+
+    match value.parse::<u32>() {
+        Ok(n) if n > 0 => Ok(Some(n)),
+        _ => Err(invalid(key, format!("{value} must be a positive integer"))),
+    }
+}
+
+/// Returns the frp load balancing group and its key, which must be set together.
+pub fn lb_group(annotations: &BTreeMap<String, String>) -> Result<Option<(String, String)>, Error> {
+    match (annotations.get(LB_GROUP), annotations.get(LB_GROUP_KEY)) {
+        (None, None) => Ok(None),
+        (Some(group), Some(key)) if !group.is_empty() && !key.is_empty() => {
+            Ok(Some((group.to_owned(), key.to_owned())))
+        }
+        (Some(group), _) if group.is_empty() => Err(invalid(LB_GROUP, "must not be empty")),
+        (Some(_), _) => Err(invalid(LB_GROUP_KEY, format!("required with {LB_GROUP}"))),
+        (None, Some(_)) => Err(invalid(LB_GROUP_KEY, format!("requires {LB_GROUP}"))),
+    }
+}
+
+/// Whether a Service opted in to be tunneled without being an frp load
+/// balancer, `load-balancer` is accepted as an alias of `expose`.
+pub fn expose(annotations: &BTreeMap<String, String>) -> bool {
+    [EXPOSE, LOAD_BALANCER]
+        .iter()
+        .any(|key| annotations.get(*key).map(String::as_str) == Some("true"))
+}
+
+/// Returns the custom domains listed in `http-domains`, a leading `*.` is
+/// allowed for wildcard domains.
+pub fn http_domains(annotations: &BTreeMap<String, String>) -> Result<Option<Vec<String>>, Error> {
+    let Some(value) = annotations.get(HTTP_DOMAINS) else {
+        return Ok(None);
+    };
+
+    let domains: Vec<String> = value
+        .split(',')
+        .map(str::trim)
+        .filter(|domain| !domain.is_empty())
+        .map(str::to_string)
+        .collect();
+
+    if domains.is_empty() {
+        return Err(invalid(HTTP_DOMAINS, "must list at least one domain"));
+    }
+
+    if let Some(domain) = domains
+        .iter()
+        .find(|domain| !is_dns_name(domain.strip_prefix("*.").unwrap_or(domain)))
+    {
+        return Err(invalid(
+            HTTP_DOMAINS,
+            format!("{domain} must be a lowercase DNS name"),
+        ));
+    }
+
+    Ok(Some(domains))
+}
+
+/// Returns the proxy name template, which may only use the `{{ns}}`,
+/// `{{name}}` and `{{port}}` placeholders.
+pub fn proxy_name_template(annotations: &BTreeMap<String, String>) -> Result<Option<&str>, Error> {
+    let Some(value) = annotations.get(PROXY_NAME_TEMPLATE) else {
+        return Ok(None);
+    };
+
+    let rendered = ["{{ns}}", "{{name}}", "{{port}}"]
+        .iter()
+        .fold(value.to_owned(), |rendered, placeholder| {
+            rendered.replace(placeholder, "x")
+        });
+
+    if rendered.contains("{{") || rendered.contains("}}") {
+        return Err(invalid(
+            PROXY_NAME_TEMPLATE,
+            format!("{value} may only use {{{{ns}}}}, {{{{name}}}} and {{{{port}}}}"),
+        ));
+    }
+
+    if rendered.is_empty()
+        || !rendered
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+    {
+        return Err(invalid(
+            PROXY_NAME_TEMPLATE,
+            format!("{value} must render to letters, digits, '-', '_' or '.'"),
+        ));
+    }
+
+    Ok(Some(value.as_str()))
+}
+
+/// Proxy settings a `proxy-snippet` may set. Where the proxy connects to
+/// (localIP, localPort, plugin), how frps binds it and which group it joins
+/// stay owned by the operator, or a Service could open a proxy into the
+/// cluster network.
+const PROXY_SNIPPET_KEYS: [&str; 9] = [
+    "transport",
+    "healthCheck",
+    "metadatas",
+    "annotations",
+    "hostHeaderRewrite",
+    "requestHeaders",
+    "responseHeaders",
+    "httpUser",
+    "httpPassword",
+];
+
+/// Parses the TOML merged into every generated proxy, which may only carry
+/// the keys of [`PROXY_SNIPPET_KEYS`].
+pub fn proxy_snippet(annotations: &BTreeMap<String, String>) -> Result<Option<toml::Table>, Error> {
+    let Some(value) = annotations.get(PROXY_SNIPPET) else {
+        return Ok(None);
+    };
+
+    let snippet: toml::Table = toml::from_str(value)
+        .map_err(|err| invalid(PROXY_SNIPPET, format!("invalid TOML: {err}")))?;
+
+    if let Some(key) = snippet
+        .keys()
+        .find(|key| !PROXY_SNIPPET_KEYS.contains(&key.as_str()))
+    {
+        return Err(invalid(
+            PROXY_SNIPPET,
+            format!(
+                "{key} can't be set, only {} are allowed",
+                PROXY_SNIPPET_KEYS.join(", ")
+            ),
+        ));
+    }
+
+    Ok(Some(snippet))
+}
+
+/// Parses a duration such as `30m`, `24h` or `7d`.
+pub fn expire_after(annotations: &BTreeMap<String, String>) -> Result<Option<Duration>, Error> {
+    let Some(value) = annotations.get(EXPIRE_AFTER) else {
+        return Ok(None);
+    };
+
+    let unit = match value.chars().last() {
+        Some('s') => 1,
+        Some('m') => 60,
+        Some('h') => 60 * 60,
+        Some('d') => 24 * 60 * 60,
+        _ => 0,
+    };
+
+    let number = value
+        .get(..value.len().saturating_sub(1))
+        .unwrap_or_default();
+
+    match number.parse::<u64>().ok().and_then(|n| n.checked_mul(unit)) {
+        Some(secs) if secs > 0 => Ok(Some(Duration::from_secs(secs))),
+        _ => Err(invalid(
+            EXPIRE_AFTER,
+            format!("{value} must be a positive integer followed by s, m, h or d"),
+        )),
+    }
+}
+
+pub fn expires_at(annotations: &BTreeMap<String, String>) -> Result<Option<DateTime<Utc>>, Error> {
+    let Some(value) = annotations.get(EXPIRES_AT) else {
+        return Ok(None);
+    };
+
+    DateTime::parse_from_rfc3339(value)
+        .map(|expires_at| Some(expires_at.with_timezone(&Utc)))
+        .map_err(|err| {
+            invalid(
+                EXPIRES_AT,
+                format!("{value} is not an RFC 3339 time: {err}"),
+            )
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn annotations(entries: &[(&str, &str)]) -> BTreeMap<String, String> {
+        entries
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn port_map_parses_pairs_and_json() {
+        let expected = BTreeMap::from([("dns".to_string(), 5353), ("27015".to_string(), 27015)]);
+
+        assert_eq!(
+            port_map(&annotations(&[(PORT_MAP, "dns:5353, 27015:27015,")])).unwrap(),
+            expected
+        );
+        assert_eq!(
+            port_map(&annotations(&[(
+                PORT_MAP,
+                r#"{"dns": 5353, "27015": "27015"}"#
+            )]))
+            .unwrap(),
+            expected
+        );
+        assert!(port_map(&annotations(&[])).unwrap().is_empty());
+    }
+
+    #[test]
+    fn port_map_rejects_invalid_entries() {
+        for value in ["dns", "dns:0", "dns:65536", "{\"dns\": true}", "{"] {
+            assert!(
+                port_map(&annotations(&[(PORT_MAP, value)])).is_err(),
+                "{value}"
+            );
+        }
+    }
+
+    #[test]
+    fn remote_port_prefers_the_most_specific_annotation() {
+        let annotations = annotations(&[
+            ("frp-operator.io/remote-port-dns", "5353"),
+            ("frp-operator.io/remote-port-53-udp", "5354"),
+            ("frp-operator.io/remote-port-53", "5355"),
+            (PORT_MAP, "53:5356"),
+        ]);
+
+        assert_eq!(
+            remote_port(&annotations, Some("dns"), 53, "UDP", false).unwrap(),
+            Some(5353)
+        );
+        assert_eq!(
+            remote_port(&annotations, Some("dns-tcp"), 53, "UDP", false).unwrap(),
+            Some(5354)
+        );
+        assert_eq!(
+            remote_port(&annotations, Some("dns-tcp"), 53, "TCP", false).unwrap(),
+            Some(5355)
+        );
+    }
+
+    #[test]
+    fn remote_port_falls_back_to_port_map_and_remote_port() {
+        let mapped = annotations(&[(PORT_MAP, "game:27015")]);
+        assert_eq!(
+            remote_port(&mapped, Some("game"), 7777, "UDP", false).unwrap(),
+            Some(27015)
+        );
+        assert_eq!(
+            remote_port(&mapped, Some("query"), 7778, "UDP", false).unwrap(),
+            None
+        );
+
+        let single = annotations(&[(REMOTE_PORT, "25565")]);
+        assert_eq!(
+            remote_port(&single, None, 25565, "TCP", true).unwrap(),
+            Some(25565)
+        );
+        assert!(remote_port(&single, None, 25565, "TCP", false).is_err());
+    }
+
+    #[test]
+    fn proxy_snippet_only_allows_listed_keys() {
+        let snippet = |value: &str| proxy_snippet(&annotations(&[(PROXY_SNIPPET, value)]));
+
+        assert!(snippet("transport.useCompression = true\nmetadatas = { team = \"a\" }").is_ok());
+        assert!(snippet("[healthCheck]\ntype = \"tcp\"").is_ok());
+        for value in [
+            "name = \"other\"",
+            "type = \"http_proxy\"",
+            "localIP = \"10.0.0.1\"",
+            "localPort = 22",
+            "plugin = { type = \"socks5\" }",
+            "loadBalancer = { group = \"a\", groupKey = \"b\" }",
+            "remotePort = 22",
+        ] {
+            assert!(snippet(value).is_err(), "{value}");
+        }
+    }
+
+    #[test]
+    fn expire_after_parses_units() {
+        for (value, secs) in [("30s", 30), ("30m", 1800), ("24h", 86400), ("7d", 604800)] {
+            assert_eq!(
+                expire_after(&annotations(&[(EXPIRE_AFTER, value)])).unwrap(),
+                Some(Duration::from_secs(secs))
+            );
+        }
+        assert_eq!(expire_after(&annotations(&[])).unwrap(), None);
+    }
+
+    #[test]
+    fn expire_after_rejects_invalid_durations() {
+        for value in ["", "0m", "m", "10", "10w", "-1h", "1.5h"] {
+            assert!(
+                expire_after(&annotations(&[(EXPIRE_AFTER, value)])).is_err(),
+                "{value}"
+            );
+        }
+    }
+}
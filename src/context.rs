@@ -1,3 +1,195 @@
+use std::{collections::BTreeMap, str::FromStr, time::Duration};
+
+use k8s_openapi::api::{
+    core::v1::{Namespace, Service},
+    discovery::v1::EndpointSlice,
+    networking::v1::Ingress,
+};
+use kube::{
+    runtime::reflector::{ObjectRef, Store},
+    ResourceExt,
+};
+use ring::hmac;
+
+use crate::{frpc::config::LoadBalancer, frps::Dashboard};
+
+/// One requirement of an equality based label selector, e.g. `tier=edge`,
+/// `tier!=internal`, `tunnel` or `!tunnel`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum LabelRequirement {
+    Equals(String, String),
+    NotEquals(String, String),
+    Exists(String),
+    NotExists(String),
+}
+
+impl LabelRequirement {
+    pub fn matches(&self, labels: &BTreeMap<String, String>) -> bool {
+        match self {
+            Self::Equals(key, value) => labels.get(key) == Some(value),
+            Self::NotEquals(key, value) => labels.get(key) != Some(value),
+            Self::Exists(key) => labels.contains_key(key),
+            Self::NotExists(key) => !labels.contains_key(key),
+        }
+    }
+}
+
+impl FromStr for LabelRequirement {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let requirement = if let Some((key, value)) = s.split_once("!=") {
+            Self::NotEquals(key.trim().to_string(), value.trim().to_string())
+        } else if let Some((key, value)) = s.split_once("==").or_else(|| s.split_once('=')) {
+            Self::Equals(key.trim().to_string(), value.trim().to_string())
+        } else if let Some(key) = s.strip_prefix('!') {
+            Self::NotExists(key.trim().to_string())
+        } else {
+            Self::Exists(s.to_string())
+        };
+
+        match &requirement {
+            Self::Equals(key, _)
+            | Self::NotEquals(key, _)
+            | Self::Exists(key)
+            | Self::NotExists(key)
+                if key.is_empty() =>
+            {
+                Err(format!("invalid label selector requirement {s}"))
+            }
+            _ => Ok(requirement),
+        }
+    }
+}
+
+#[derive(Default, Debug, Clone)]
+pub struct Options {
+    pub consolidate_proxies: bool,
+    pub vhost_http_port: u16,
+    pub vhost_https_port: u16,
+    pub default_domain: Option<String>,
+    pub metadata_labels: Vec<String>,
+    pub metadata_annotations: Vec<String>,
+    pub dashboard: Option<Dashboard>,
+    pub stats_interval: Duration,
+    pub reconcile_debounce: Duration,
+    pub watch_namespaces: Vec<String>,
+    pub exclude_namespaces: Vec<String>,
+    pub ingress_selector: Vec<LabelRequirement>,
+    pub service_selector: Vec<LabelRequirement>,
+    pub namespace_selector: Vec<LabelRequirement>,
+    pub strict_config: bool,
+    pub unmanaged: bool,
+    pub default_lb_group: Option<LoadBalancer>,
+    /// frps addresses in order of priority, frpc fails over to the next one
+    /// when it can't log in.
+    pub server_addrs: Vec<String>,
+    pub pod_name: Option<String>,
+    pub pod_namespace: Option<String>,
+    pub group_key_secret: Vec<u8>,
+}
+
+impl Options {
+    /// Whether the namespace is listed for the controllers to act on, an empty
+    /// watch list allows every namespace that isn't excluded.
+    pub fn is_watched_namespace(&self, ns: &str) -> bool {
+        (self.watch_namespaces.is_empty() || self.watch_namespaces.iter().any(|n| n == ns))
+            && !self.exclude_namespaces.iter().any(|n| n == ns)
+    }
+
+    /// Whether the labels satisfy every requirement of the selector, an empty
+    /// selector matches everything.
+    pub fn is_selected(selector: &[LabelRequirement], labels: &BTreeMap<String, String>) -> bool {
+        selector
+            .iter()
+            .all(|requirement| requirement.matches(labels))
+    }
+
+    /// Derives the key of a load balancing group the operator names itself
+    /// from the group key secret, so other clients of a shared frps can't
+    /// join the group by guessing its name.
+    pub fn group_key(&self, group: &str) -> String {
+        let key = hmac::Key::new(hmac::HMAC_SHA256, &self.group_key_secret);
+        hmac::sign(&key, group.as_bytes())
+            .as_ref()
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect()
+    }
+}
+
 pub struct Context {
     pub client: kube::Client,
+    pub options: Options,
+    pub ingresses: Store<Ingress>,
+    pub services: Store<Service>,
+    pub endpoint_slices: Store<EndpointSlice>,
+    /// Only filled when namespaces are selected by label.
+    pub namespaces: Store<Namespace>,
+}
+
+impl Context {
+    /// Whether the controllers act on resources in the namespace, it has to be
+    /// listed and its labels have to match the namespace selector.
+    pub async fn is_watched_namespace(&self, ns: &str) -> bool {
+        if !self.options.is_watched_namespace(ns) {
+            return false;
+        }
+
+        let selector = &self.options.namespace_selector;
+        if selector.is_empty() {
+            return true;
+        }
+
+        // an unsynced store would release every resource
+        if self.namespaces.wait_until_ready().await.is_err() {
+            return false;
+        }
+
+        self.namespaces
+            .get(&ObjectRef::new(ns))
+            .is_some_and(|namespace| Options::is_selected(selector, namespace.labels()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn label_requirement_parses_every_operator() {
+        let requirement = |s: &str| s.parse::<LabelRequirement>();
+
+        assert_eq!(
+            requirement("tier=edge"),
+            Ok(LabelRequirement::Equals("tier".into(), "edge".into()))
+        );
+        assert_eq!(
+            requirement(" tier == edge "),
+            Ok(LabelRequirement::Equals("tier".into(), "edge".into()))
+        );
+        assert_eq!(
+            requirement("tier!=internal"),
+            Ok(LabelRequirement::NotEquals(
+                "tier".into(),
+                "internal".into()
+            ))
+        );
+        assert_eq!(
+            requirement("tunnel"),
+            Ok(LabelRequirement::Exists("tunnel".into()))
+        );
+        assert_eq!(
+            requirement("!tunnel"),
+            Ok(LabelRequirement::NotExists("tunnel".into()))
+        );
+    }
+
+    #[test]
+    fn label_requirement_rejects_empty_keys() {
+        for s in ["", "=edge", "!=edge", "!"] {
+            assert!(s.parse::<LabelRequirement>().is_err(), "{s}");
+        }
+    }
 }
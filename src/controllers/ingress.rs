@@ -1,27 +1,43 @@
-use std::{collections::HashMap, sync::Arc, time::Duration};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 
 use futures_util::StreamExt;
 use k8s_openapi::api::{
-    core::v1::{Secret, Service},
+    core::v1::Secret,
     networking::v1::{
-        Ingress, IngressLoadBalancerIngress, IngressLoadBalancerStatus, IngressPortStatus,
-        IngressStatus,
+        HTTPIngressPath, HTTPIngressRuleValue, Ingress, IngressLoadBalancerIngress,
+        IngressLoadBalancerStatus, IngressPortStatus, IngressRule, IngressSpec, IngressStatus,
     },
 };
 use kube::{
     api::{Patch, PatchParams},
-    runtime::{controller::Action, finalizer, reflector, watcher, Controller, WatchStreamExt},
+    runtime::{
+        controller::{self, Action},
+        events::EventType,
+        finalizer, reflector,
+        reflector::{store::Writer, ObjectRef},
+        watcher, Controller, WatchStreamExt,
+    },
     Api, ResourceExt,
 };
 use log::{error, info, warn};
+use serde_json::json;
 use tokio::fs;
 
 use crate::{
-    context::Context,
+    annotations::{self, BackendProtocol},
+    context::{Context, Options},
+    controllers::{
+        apply_default_lb_group, cached, proxy_metadatas, proxy_transport, publish_event,
+        reconcile_trigger, service, watch_namespaces,
+    },
     error::Error,
     frpc::{
         self,
-        config::{Proxy, ProxyConfig, ProxyPlugin},
+        config::{LoadBalancer, Proxy, ProxyConfig, ProxyPlugin},
     },
     OPERATOR_MANAGER,
 };
@@ -29,30 +45,221 @@ use anyhow::anyhow;
 
 pub const INGRESS_FINALIZER: &str = "frp-operator.io/ingress-finalizer";
 
+pub fn is_frp_ingress(ing: &Ingress) -> bool {
+    ing.annotations()
+        .get("kubernetes.io/ingress.class")
+        .or(ing
+            .spec
+            .as_ref()
+            .and_then(|spec| spec.ingress_class_name.as_ref()))
+        .map(String::as_str)
+        == Some("frp")
+}
+
+pub fn validate_ingress(ing: &Ingress) -> Result<(), Error> {
+    let annotations = ing.annotations();
+
+    proxy_transport(annotations)?;
+    annotations::backend_protocol(annotations)?;
+    annotations::canary_weight(annotations)?;
+
+    if annotations::subdomain(annotations)?.is_some()
+        && ing
+            .spec
+            .as_ref()
+            .and_then(|spec| spec.rules.as_ref())
+            .into_iter()
+            .flatten()
+            .any(|rule| rule.host.is_some())
+    {
+        return Err(Error::InvalidAnnotation(
+            annotations::SUBDOMAIN.to_string(),
+            "cannot be combined with rules that set a host".to_string(),
+        ));
+    }
+
+    Ok(())
+}
+
+/// frp spreads traffic evenly across the members of a group, so a weight is
+/// approximated by the number of members each side contributes.
+fn canary_members(weight: u8) -> (usize, usize) {
+    let canary = (weight as usize + 5) / 10;
+    let stable = 10 - canary;
+
+    let (mut a, mut b) = (stable, canary);
+    while b != 0 {
+        (a, b) = (b, a % b);
+    }
+    let gcd = a;
+
+    (stable / gcd, canary / gcd)
+}
+
+fn rule_hosts(ing: &Ingress) -> Vec<String> {
+    ing.spec
+        .iter()
+        .flat_map(|spec| spec.rules.iter().flatten())
+        .filter_map(|rule| rule.host.clone())
+        .collect()
+}
+
+fn is_canary(ing: &Ingress) -> bool {
+    is_frp_ingress(ing) && matches!(annotations::canary_weight(ing.annotations()), Ok(Some(_)))
+}
+
+/// Collects the weight of every canary Ingress sharing the namespace, keyed by
+/// host, from the store so a reconcile doesn't list the namespace.
+async fn canary_weights(ing: &Ingress, ctx: &Context) -> Result<HashMap<String, u8>, Error> {
+    let mut weights = HashMap::new();
+
+    ctx.ingresses
+        .wait_until_ready()
+        .await
+        .map_err(|err| anyhow!("ingress store is gone: {err}"))?;
+
+    for other in ctx.ingresses.state() {
+        if other.namespace() != ing.namespace()
+            || other.name_any() == ing.name_any()
+            || other.metadata.deletion_timestamp.is_some()
+            || !is_frp_ingress(&other)
+        {
+            continue;
+        }
+
+        let Ok(Some(weight)) = annotations::canary_weight(other.annotations()) else {
+            continue;
+        };
+
+        for host in rule_hosts(&other) {
+            weights.insert(host, weight);
+        }
+    }
+
+    Ok(weights)
+}
+
+fn proxy_local_addr(proxy: &Proxy) -> Option<String> {
+    proxy
+        .plugin
+        .as_ref()
+        .and_then(|plugin| plugin.local_addr.clone())
+        .or_else(|| {
+            proxy
+                .local_ip
+                .as_ref()
+                .zip(proxy.local_port)
+                .map(|(ip, port)| frpc::join_host_port(ip, port))
+        })
+}
+
+/// Maps every rule host to the secret terminating its tls. A tls entry without
+/// hosts covers every rule host not listed explicitly elsewhere.
+fn tls_secrets(spec: &IngressSpec) -> HashMap<String, String> {
+    let explicit: Vec<&String> = spec
+        .tls
+        .iter()
+        .flatten()
+        .flat_map(|tls| tls.hosts.iter().flatten())
+        .collect();
+
+    let mut secrets = HashMap::new();
+    for tls in spec.tls.iter().flatten() {
+        let Some(secret_name) = tls.secret_name.as_ref() else {
+            continue;
+        };
+
+        match tls.hosts.as_ref().filter(|hosts| !hosts.is_empty()) {
+            Some(hosts) => {
+                for host in hosts {
+                    secrets.insert(host.to_owned(), secret_name.to_owned());
+                }
+            }
+            None => {
+                for host in spec
+                    .rules
+                    .iter()
+                    .flatten()
+                    .filter_map(|rule| rule.host.as_ref())
+                    .filter(|host| !explicit.contains(host))
+                {
+                    secrets.insert(host.to_owned(), secret_name.to_owned());
+                }
+            }
+        }
+    }
+
+    secrets
+}
+
 pub async fn proxy_from_ingress(
     ing: &Ingress,
-    client: &kube::Client,
+    ctx: &Context,
     secrets: &mut Vec<Secret>,
 ) -> Result<ProxyConfig, Error> {
+    let client = &ctx.client;
+    let options = &ctx.options;
     let mut config = ProxyConfig {
         name: ing.name_any(),
         proxies: vec![],
     };
 
+    validate_ingress(ing)?;
+
+    let transport = proxy_transport(ing.annotations())?;
+    let backend_protocol = annotations::backend_protocol(ing.annotations())?;
+    let subdomain = annotations::subdomain(ing.annotations())?;
+    let canary_weight = annotations::canary_weight(ing.annotations())?;
+    let metadatas = proxy_metadatas(ing, options);
+
     let ns: String = ing.namespace().unwrap_or("default".to_string());
-    let svc_api: Api<Service> = Api::namespaced(client.clone(), &ns);
-    let secret_api: Api<Secret> = Api::namespaced(client.clone(), &ns);
 
-    let rules = ing.spec.as_ref().unwrap().rules.as_ref().unwrap();
-    for rule in rules {
-        let custom_domains = rule.host.as_ref().map(|h| vec![h.to_owned()]);
-        let paths = &rule.http.as_ref().unwrap().paths;
-        for path in paths {
-            let backend_svc = path.backend.service.as_ref().unwrap();
+    let mut secret_names = vec![];
+
+    let spec = ing
+        .spec
+        .as_ref()
+        .ok_or_else(|| anyhow!("ingress has no spec"))?;
+
+    // the default backend behaves like a host-less rule matching every path
+    let default_rule = spec.default_backend.as_ref().map(|backend| IngressRule {
+        host: None,
+        http: Some(HTTPIngressRuleValue {
+            paths: vec![HTTPIngressPath {
+                backend: backend.clone(),
+                path: None,
+                path_type: "ImplementationSpecific".to_string(),
+            }],
+        }),
+    });
+
+    let tls_map = tls_secrets(spec);
+
+    let rules = spec.rules.iter().flatten().map(|rule| (rule, "default"));
+    for (rule, fallback_label) in
+        rules.chain(default_rule.iter().map(|rule| (rule, "default-backend")))
+    {
+        let custom_domains = match (rule.host.as_ref(), subdomain.as_ref()) {
+            (Some(host), _) => Some(vec![host.to_owned()]),
+            (None, Some(_)) => None,
+            (None, None) => Some(vec![options.default_domain.clone().ok_or_else(|| {
+                anyhow!(
+                    "rule without host requires a default domain or the {} annotation",
+                    annotations::SUBDOMAIN
+                )
+            })?]),
+        };
+        let tls_secret = rule.host.as_ref().and_then(|host| tls_map.get(host));
+        let paths = rule.http.iter().flat_map(|http| http.paths.iter());
+        for (i, path) in paths.enumerate() {
+            let backend_svc = path
+                .backend
+                .service
+                .as_ref()
+                .ok_or_else(|| anyhow!("only service backends are supported"))?;
             let backend_svc_port = backend_svc.port.as_ref().unwrap();
             let svc_name = &backend_svc.name;
-            let svc = svc_api
-                .get(&svc_name)
+            let svc = cached(&ctx.services, client, &ns, svc_name)
                 .await
                 .map_err(|err| anyhow!("failed to get service {svc_name}: {err}"))?;
             let svc_spec = svc.spec.as_ref().unwrap();
@@ -72,84 +279,257 @@ pub async fn proxy_from_ingress(
                 return Err(anyhow!("failed to find port").into());
             };
 
-            let locations = path.path.as_ref().map(|p| vec![p.to_owned()]);
+            // https proxies are routed by SNI only, so paths can't be told apart
+            let locations = path
+                .path
+                .as_ref()
+                .filter(|_| tls_secret.is_none())
+                .map(|p| vec![p.to_owned()]);
+            let local_ip = format!("{svc_name}.{ns}.svc.cluster.local");
+            let local_addr = frpc::join_host_port(&local_ip, port);
+
+            if options.consolidate_proxies {
+                if let Some(proxy) = config.proxies.iter_mut().find(|proxy| {
+                    proxy.custom_domains == custom_domains
+                        && proxy_local_addr(proxy).as_ref() == Some(&local_addr)
+                }) {
+                    // a proxy without locations already matches every path
+                    match (proxy.locations.as_mut(), locations) {
+                        (Some(existing), Some(locations)) => existing.extend(locations),
+                        _ => proxy.locations = None,
+                    }
+                    continue;
+                }
+            }
+
+            let host = rule.host.as_deref().unwrap_or(fallback_label);
+            let name = if options.consolidate_proxies {
+                format!("ing-{}-{host}-{svc_name}-{port}", ing.name_any())
+            } else {
+                format!("ing-{}-{host}-{i}", ing.name_any())
+            };
 
-            config.proxies.push(Proxy {
-                name: format!("ing-{}", ing.name_any()),
+            let mut proxy = Proxy {
+                name,
                 type_: "http".to_string(),
-                local_ip: Some(format!("{svc_name}.{ns}.svc.cluster.local")),
+                local_ip: Some(local_ip),
                 local_port: Some(port),
                 custom_domains: custom_domains.to_owned(),
+                subdomain: subdomain.clone().filter(|_| custom_domains.is_none()),
                 locations,
+                transport: transport.clone(),
+                metadatas: metadatas.clone(),
                 ..Proxy::default()
-            });
-        }
-    }
-
-    let mut tls_map = HashMap::new();
-    for ing in ing.spec.as_ref().unwrap().tls.iter().flatten() {
-        for host in ing.hosts.as_ref().unwrap() {
-            tls_map.insert(host.to_string(), ing.secret_name.clone().unwrap());
-        }
-    }
+            };
 
-    for proxy in config.proxies.iter_mut() {
-        for domain in proxy.custom_domains.as_ref().unwrap() {
-            if let Some(secret_name) = tls_map.get(domain) {
-                let secret = secret_api.get(secret_name).await?;
+            if let Some(secret_name) = tls_secret {
+                // the copied certificates only exist in the operator pod
+                if options.unmanaged {
+                    return Err(anyhow!(
+                        "tls secret {secret_name} can't be terminated by an unmanaged frpc"
+                    )
+                    .into());
+                }
 
-                secrets.push(secret);
+                if !secret_names.contains(secret_name) {
+                    secret_names.push(secret_name.to_owned());
+                }
 
                 proxy.type_ = "https".to_string();
                 proxy.plugin = Some(ProxyPlugin {
-                    type_: "https2http".to_string(),
-                    local_addr: proxy
-                        .local_ip
-                        .as_ref()
-                        .zip(proxy.local_port)
-                        .map(|(ip, port)| format!("{ip}:{port}")),
+                    type_: match backend_protocol {
+                        BackendProtocol::Http => "https2http",
+                        BackendProtocol::Https => "https2https",
+                    }
+                    .to_string(),
+                    local_addr: Some(local_addr),
                     crt_path: Some(format!("/etc/ssl/certs/{secret_name}/tls.crt")),
                     key_path: Some(format!("/etc/ssl/certs/{secret_name}/tls.key")),
                     secret_name: Some(secret_name.to_owned()),
                     ..ProxyPlugin::default()
                 });
+            } else if backend_protocol == BackendProtocol::Https {
+                proxy.plugin = Some(ProxyPlugin {
+                    type_: "http2https".to_string(),
+                    local_addr: Some(local_addr),
+                    ..ProxyPlugin::default()
+                });
+            }
 
-                proxy.locations = None;
+            if proxy.plugin.is_some() {
                 proxy.local_ip = None;
                 proxy.local_port = None;
             }
+
+            config.proxies.push(proxy);
         }
     }
 
+    let secret_api: Api<Secret> = Api::namespaced(client.clone(), &ns);
+    for secret_name in secret_names {
+        let secret = secret_api
+            .get(&secret_name)
+            .await
+            .map_err(|err| anyhow!("failed to get secret {ns}/{secret_name}: {err}"))?;
+        secrets.push(secret);
+    }
+
+    let canary_weights = if canary_weight.is_some() {
+        HashMap::new()
+    } else {
+        canary_weights(ing, ctx).await?
+    };
+
+    let mut proxies = vec![];
+    for proxy in config.proxies.drain(..) {
+        let host = proxy.custom_domains.iter().flatten().next();
+        let weight =
+            canary_weight.or_else(|| host.and_then(|host| canary_weights.get(host).copied()));
+
+        let (Some(host), Some(weight)) = (host, weight) else {
+            proxies.push(proxy);
+            continue;
+        };
+
+        let (stable, canary) = canary_members(weight);
+        let members = if canary_weight.is_some() {
+            canary
+        } else {
+            stable
+        };
+        let group = format!("canary-{ns}-{host}");
+
+        for i in 0..members {
+            proxies.push(Proxy {
+                name: format!("{}-{i}", proxy.name),
+                load_balancer: Some(LoadBalancer {
+                    group: group.clone(),
+                    group_key: options.group_key(&group),
+                }),
+                ..proxy.clone()
+            });
+        }
+    }
+    config.proxies = proxies;
+
+    apply_default_lb_group(options, &mut config.proxies);
+
     Ok(config)
 }
 
-async fn reconcile(obj: Arc<Ingress>, ctx: Arc<Context>) -> Result<Action, Error> {
-    if !obj
-        .annotations()
-        .get("kubernetes.io/ingress.class")
-        .or(obj
-            .spec
-            .as_ref()
-            .and_then(|spec| spec.ingress_class_name.as_ref()))
-        .map_or(false, |ic| ic == "frp")
+fn status_ports(config: &ProxyConfig, options: &Options) -> Vec<IngressPortStatus> {
+    [
+        ("http", options.vhost_http_port),
+        ("https", options.vhost_https_port),
+    ]
+    .into_iter()
+    .filter(|(type_, _)| config.proxies.iter().any(|proxy| proxy.type_ == *type_))
+    .map(|(_, port)| IngressPortStatus {
+        port: port as i32,
+        protocol: "TCP".to_string(),
+        ..IngressPortStatus::default()
+    })
+    .collect()
+}
+
+/// Removes the proxy file and copied TLS secrets of the Ingress and reloads frpc.
+async fn remove_proxy(ing: &Ingress, ctx: &Context) -> Result<(), Error> {
+    frpc::remove_config_proxy_file(&ing.name_any()).await?;
+
+    for secret_name in ing
+        .spec
+        .as_ref()
+        .and_then(|spec| spec.tls.clone())
+        .iter()
+        .flatten()
+        .filter_map(|s| s.secret_name.clone())
     {
-        return Ok(Action::await_change());
+        fs::remove_dir_all(format!("/etc/ssl/certs/{secret_name}")).await?;
     }
 
+    frpc::reload(&ctx.options).await
+}
+
+/// Tears down an Ingress the operator no longer serves.
+async fn release(ing: &Ingress, ingress_api: &Api<Ingress>, ctx: &Context) -> Result<(), Error> {
+    let name = ing.name_any();
+
+    info!("ingress {name} is no longer served by frp, releasing");
+
+    if frpc::has_config_proxy_file(&name).await {
+        remove_proxy(ing, ctx).await?;
+    }
+
+    let published = ing
+        .status
+        .as_ref()
+        .and_then(|status| status.load_balancer.as_ref())
+        .is_some_and(|load_balancer| load_balancer.ingress.is_some());
+    if published {
+        ingress_api
+            .patch_status(
+                &name,
+                &PatchParams::apply(OPERATOR_MANAGER),
+                &Patch::Merge(json!({
+                    "status": {
+                        "loadBalancer": {
+                            "ingress": null,
+                        },
+                    },
+                })),
+            )
+            .await?;
+    }
+
+    let finalizers: Vec<&String> = ing
+        .finalizers()
+        .iter()
+        .filter(|finalizer| *finalizer != INGRESS_FINALIZER)
+        .collect();
+
+    ingress_api
+        .patch(
+            &name,
+            &PatchParams::default(),
+            &Patch::Merge(json!({
+                "metadata": {
+                    "finalizers": finalizers,
+                    "resourceVersion": ing.resource_version(),
+                },
+            })),
+        )
+        .await?;
+
+    Ok(())
+}
+
+async fn reconcile(obj: Arc<Ingress>, ctx: Arc<Context>) -> Result<Action, Error> {
     let obj_name = obj.name_any().to_owned();
     let obj_ns = obj.namespace().unwrap_or("default".to_string());
 
     let client = ctx.client.clone();
     let ingress_api: Api<Ingress> = Api::namespaced(client.clone(), &obj_ns);
 
+    // Ingresses moved to another class, out of the watched namespaces or out of
+    // the selector lose their tunnel, like Services do
+    if !is_frp_ingress(&obj)
+        || !ctx.is_watched_namespace(&obj_ns).await
+        || !Options::is_selected(&ctx.options.ingress_selector, obj.labels())
+    {
+        if obj.finalizers().iter().any(|f| f == INGRESS_FINALIZER) {
+            release(&obj, &ingress_api, &ctx).await?;
+        }
+
+        return Ok(Action::await_change());
+    }
+
     finalizer(&ingress_api, INGRESS_FINALIZER, obj, |event| async {
         match event {
             finalizer::Event::Apply(ing) => {
                 let mut secrets = vec![];
-                let config = proxy_from_ingress(&ing, &client, &mut secrets).await?;
+                let config = proxy_from_ingress(&ing, &ctx, &mut secrets).await?;
 
-                frpc::write_config_proxy_to_file(config).await?;
+                let ports = status_ports(&config, &ctx.options);
 
                 for secret in secrets {
                     // copy secret data
@@ -166,50 +546,48 @@ async fn reconcile(obj: Arc<Ingress>, ctx: Arc<Context>) -> Result<Action, Error
                     }
                 }
 
-                frpc::reload().await?;
+                // a rejected config keeps the previous proxy file, frpc isn't reloaded
+                if let Err(err) =
+                    frpc::write_config_proxy_to_file(config, ctx.options.strict_config).await
+                {
+                    if let Error::InvalidConfig(reason) = &err {
+                        publish_event(
+                            &client,
+                            ing.as_ref(),
+                            EventType::Warning,
+                            "InvalidConfig",
+                            reason.clone(),
+                        )
+                        .await?;
+                    }
+                    return Err(err);
+                }
+
+                frpc::reload(&ctx.options).await?;
 
-                let mut ing = ingress_api.get_status(&obj_name).await?;
-                ing.status = Some(IngressStatus {
+                let (ip, hostname) = service::load_balancer_address(None).await;
+                let status = IngressStatus {
                     load_balancer: Some(IngressLoadBalancerStatus {
                         ingress: Some(vec![IngressLoadBalancerIngress {
-                            // hostname: todo!(),
-                            ip: frpc::read_config_from_file()
-                                .await
-                                .map(|config| config.server_addr)
-                                .ok(),
-                            ports: Some(vec![IngressPortStatus {
-                                port: 80,
-                                protocol: "TCP".to_string(),
-                                ..IngressPortStatus::default()
-                            }]),
-                            ..IngressLoadBalancerIngress::default()
+                            hostname,
+                            ip,
+                            ports: Some(ports),
                         }]),
                     }),
-                });
-
-                ingress_api
-                    .patch_status(
-                        &obj_name,
-                        &PatchParams::apply(OPERATOR_MANAGER),
-                        &Patch::Merge(ing),
-                    )
-                    .await?;
+                };
+
+                if ing.status.as_ref() != Some(&status) {
+                    ingress_api
+                        .patch_status(
+                            &obj_name,
+                            &PatchParams::apply(OPERATOR_MANAGER),
+                            &Patch::Merge(json!({ "status": status })),
+                        )
+                        .await?;
+                }
             }
             finalizer::Event::Cleanup(ing) => {
-                frpc::remove_config_proxy_file(&ing.name_any()).await?;
-
-                for secret_name in ing
-                    .spec
-                    .as_ref()
-                    .and_then(|spec| spec.tls.clone())
-                    .iter()
-                    .flatten()
-                    .filter_map(|s| s.secret_name.clone())
-                {
-                    fs::remove_dir_all(format!("/etc/ssl/certs/{secret_name}")).await?;
-                }
-
-                frpc::reload().await?;
+                remove_proxy(&ing, &ctx).await?;
             }
         }
 
@@ -224,18 +602,60 @@ fn error_policy<K>(_obj: Arc<K>, err: &Error, _ctx: Arc<Context>) -> Action {
     Action::requeue(Duration::from_secs(15))
 }
 
-pub async fn run(ctx: Arc<Context>) -> anyhow::Result<()> {
+pub async fn run(ctx: Arc<Context>, ingresses: Writer<Ingress>) -> anyhow::Result<()> {
     let client = ctx.client.clone();
 
     let cfg = watcher::Config::default();
     let ingress_api: Api<Ingress> = Api::all(client.clone());
 
-    let (reader, writer) = reflector::store();
-    let stream = reflector(writer, watcher(ingress_api, cfg))
+    let reader = ctx.ingresses.clone();
+    let stream = reflector(ingresses, watcher(ingress_api.clone(), cfg.clone()))
         .default_backoff()
-        .touched_objects();
+        .touched_objects()
+        .predicate_filter(reconcile_trigger);
 
-    Controller::for_stream(stream, reader)
+    // a canary changing its weight must re-render the stable Ingresses sharing its hosts
+    let canary_stream = watcher(ingress_api, cfg)
+        .default_backoff()
+        .touched_objects()
+        .predicate_filter(reconcile_trigger);
+    let store = reader.clone();
+    // the hosts every canary had when last seen, so the stable Ingresses are
+    // released from the group when it stops being a canary or moves hosts
+    let canary_hosts: Mutex<HashMap<ObjectRef<Ingress>, Vec<String>>> = Mutex::default();
+
+    // bursts of edits to one object are coalesced into a single reload
+    let controller = Controller::for_stream(stream, reader)
+        .with_config(controller::Config::default().debounce(ctx.options.reconcile_debounce))
+        .watches_stream(canary_stream, move |ing| {
+            let obj_ref = ObjectRef::from_obj(&ing);
+            let mut hosts = if is_canary(&ing) && ing.metadata.deletion_timestamp.is_none() {
+                rule_hosts(&ing)
+            } else {
+                vec![]
+            };
+
+            let mut canary_hosts = canary_hosts.lock().unwrap();
+            let previous = if hosts.is_empty() {
+                canary_hosts.remove(&obj_ref)
+            } else {
+                canary_hosts.insert(obj_ref, hosts.clone())
+            };
+            hosts.extend(previous.into_iter().flatten());
+
+            store
+                .state()
+                .into_iter()
+                .filter(|other| {
+                    other.namespace() == ing.namespace()
+                        && other.name_any() != ing.name_any()
+                        && rule_hosts(other).iter().any(|host| hosts.contains(host))
+                })
+                .map(|other| ObjectRef::from_obj(other.as_ref()))
+                .collect::<Vec<_>>()
+        });
+
+    watch_namespaces(controller, &ctx, ctx.ingresses.clone())
         .shutdown_on_signal()
         .run(reconcile, error_policy, ctx.clone())
         .for_each(|res| async move {
@@ -248,3 +668,101 @@ pub async fn run(ctx: Arc<Context>) -> anyhow::Result<()> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use k8s_openapi::api::networking::v1::IngressTLS;
+
+    use super::*;
+
+    fn tls(hosts: &[&str], secret_name: &str) -> IngressTLS {
+        IngressTLS {
+            hosts: Some(hosts.iter().map(|host| host.to_string()).collect()),
+            secret_name: Some(secret_name.to_string()),
+        }
+    }
+
+    fn spec(hosts: &[&str], tls: Vec<IngressTLS>) -> IngressSpec {
+        IngressSpec {
+            rules: Some(
+                hosts
+                    .iter()
+                    .map(|host| IngressRule {
+                        host: Some(host.to_string()),
+                        http: None,
+                    })
+                    .collect(),
+            ),
+            tls: Some(tls),
+            ..IngressSpec::default()
+        }
+    }
+
+    #[test]
+    fn tls_secrets_maps_listed_hosts() {
+        let secrets = tls_secrets(&spec(
+            &["shop.example.com", "api.example.com", "plain.example.com"],
+            vec![
+                tls(&["shop.example.com"], "shop-tls"),
+                tls(&["api.example.com"], "api-tls"),
+            ],
+        ));
+
+        assert_eq!(
+            secrets,
+            HashMap::from([
+                ("shop.example.com".to_string(), "shop-tls".to_string()),
+                ("api.example.com".to_string(), "api-tls".to_string()),
+            ])
+        );
+    }
+
+    #[test]
+    fn tls_secrets_prefers_explicit_hosts_over_host_less_entries() {
+        // in either order, the host-less entry only covers the remaining rule hosts
+        for entries in [
+            vec![
+                tls(&[], "wildcard-tls"),
+                tls(&["api.example.com"], "api-tls"),
+            ],
+            vec![
+                tls(&["api.example.com"], "api-tls"),
+                tls(&[], "wildcard-tls"),
+            ],
+        ] {
+            let secrets = tls_secrets(&spec(&["shop.example.com", "api.example.com"], entries));
+
+            assert_eq!(
+                secrets,
+                HashMap::from([
+                    ("shop.example.com".to_string(), "wildcard-tls".to_string()),
+                    ("api.example.com".to_string(), "api-tls".to_string()),
+                ])
+            );
+        }
+    }
+
+    #[test]
+    fn tls_secrets_skips_entries_without_a_secret() {
+        let secrets = tls_secrets(&spec(
+            &["shop.example.com"],
+            vec![IngressTLS {
+                hosts: Some(vec!["shop.example.com".to_string()]),
+                secret_name: None,
+            }],
+        ));
+
+        assert!(secrets.is_empty());
+    }
+
+    #[test]
+    fn canary_members_reduce_the_weight_to_tenths() {
+        assert_eq!(canary_members(0), (1, 0));
+        assert_eq!(canary_members(10), (9, 1));
+        assert_eq!(canary_members(20), (4, 1));
+        assert_eq!(canary_members(50), (1, 1));
+        assert_eq!(canary_members(54), (1, 1));
+        assert_eq!(canary_members(75), (1, 4));
+        assert_eq!(canary_members(100), (0, 1));
+    }
+}
@@ -1,26 +1,526 @@
-use std::sync::Arc;
+use std::{
+    collections::{hash_map::DefaultHasher, BTreeMap, HashSet},
+    fmt::Debug,
+    hash::{Hash, Hasher},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use anyhow::anyhow;
+use futures_util::StreamExt;
+use k8s_openapi::{
+    api::{
+        core::v1::{Namespace, Pod, Secret, Service},
+        networking::v1::Ingress,
+    },
+    apimachinery::pkg::apis::meta::v1::Condition,
+    NamespaceResourceScope,
+};
+use kube::{
+    api::ListParams,
+    runtime::{
+        events::{Event, EventType, Recorder},
+        predicates,
+        reflector::{self, ObjectRef, Store},
+        watcher, Controller, WatchStreamExt,
+    },
+    Api, Resource, ResourceExt,
+};
+use log::{error, info, warn};
+use serde::{de::DeserializeOwned, Serialize};
 
 use crate::{
-    context::Context,
+    annotations,
+    context::{Context, Options},
     error::Error,
-    frpc::{self, config::ClientConfig},
+    frpc::{
+        self,
+        config::{merge_table, missing_key, ClientConfig, LoadBalancer, Proxy, ProxyTransport},
+    },
+    OPERATOR_MANAGER,
 };
 
 pub mod ingress;
 pub mod service;
+pub mod stats;
+
+/// Reads an object from a reflector store, asking the API server only when the
+/// store doesn't have it, e.g. while it's still syncing.
+pub async fn cached<K>(
+    store: &Store<K>,
+    client: &kube::Client,
+    ns: &str,
+    name: &str,
+) -> Result<Arc<K>, kube::Error>
+where
+    K: Resource<DynamicType = (), Scope = NamespaceResourceScope>
+        + Clone
+        + DeserializeOwned
+        + Debug,
+{
+    if let Some(obj) = store.get(&ObjectRef::new(name).within(ns)) {
+        return Ok(obj);
+    }
+
+    let api: Api<K> = Api::namespaced(client.clone(), ns);
+    Ok(Arc::new(api.get(name).await?))
+}
+
+/// Reads a key of a Secret. Secrets are fetched when referenced rather than
+/// cached, so the operator doesn't hold every Secret of the cluster.
+pub async fn secret_value(ctx: &Context, ns: &str, name: &str, key: &str) -> Result<String, Error> {
+    let secret_api: Api<Secret> = Api::namespaced(ctx.client.clone(), ns);
+    let secret = secret_api
+        .get(name)
+        .await
+        .map_err(|err| anyhow!("failed to get secret {ns}/{name}: {err}"))?;
+
+    let value = secret
+        .data
+        .as_ref()
+        .and_then(|data| data.get(key))
+        .ok_or_else(|| anyhow!("secret {ns}/{name} has no key {key}"))?;
+
+    String::from_utf8(value.0.clone())
+        .map_err(|err| anyhow!("secret {ns}/{name} key {key} is not valid utf-8: {err}").into())
+}
+
+pub async fn publish_event<K: Resource<DynamicType = ()>>(
+    client: &kube::Client,
+    obj: &K,
+    type_: EventType,
+    reason: &str,
+    note: String,
+) -> Result<(), Error> {
+    let recorder = Recorder::new(client.clone(), OPERATOR_MANAGER.into(), obj.object_ref(&()));
+
+    recorder
+        .publish(Event {
+            type_,
+            reason: reason.to_string(),
+            note: Some(note),
+            action: "Reconciling".to_string(),
+            secondary: None,
+        })
+        .await?;
+
+    Ok(())
+}
+
+/// Builds the proxy transport settings shared by Ingress and Service annotations.
+pub fn proxy_transport(
+    annotations: &BTreeMap<String, String>,
+) -> Result<Option<ProxyTransport>, Error> {
+    let transport = ProxyTransport {
+        proxy_protocol_version: annotations::proxy_protocol(annotations)?,
+        bandwidth_limit: annotations::bandwidth_limit(annotations)?,
+        bandwidth_limit_mode: annotations::bandwidth_limit_mode(annotations)?,
+        ..ProxyTransport::default()
+    };
+
+    Ok(Some(transport).filter(|transport| *transport != ProxyTransport::default()))
+}
+
+/// Merges the `proxy-snippet` annotation into every proxy, checking the result
+/// still deserializes so a bad snippet never reaches frpc.
+pub fn apply_proxy_snippet(
+    annotations: &BTreeMap<String, String>,
+    proxies: &mut [Proxy],
+) -> Result<(), Error> {
+    let Some(snippet) = annotations::proxy_snippet(annotations)? else {
+        return Ok(());
+    };
+
+    for proxy in proxies.iter_mut() {
+        let mut table = toml::Table::try_from(&*proxy)
+            .map_err(|err| anyhow!("failed to serialize proxy {}: {err}", proxy.name))?;
+        merge_table(&mut table, snippet.clone());
+
+        let merged: Proxy = table.try_into().map_err(|err| {
+            Error::InvalidAnnotation(annotations::PROXY_SNIPPET.to_string(), format!("{err}"))
+        })?;
+
+        // nested settings the operator models are typed, unknown keys in them would be dropped
+        let table = toml::Table::try_from(&merged)
+            .map_err(|err| anyhow!("failed to serialize proxy {}: {err}", proxy.name))?;
+        if let Some(key) = missing_key(&table, &snippet) {
+            return Err(Error::InvalidAnnotation(
+                annotations::PROXY_SNIPPET.to_string(),
+                format!("{key} is not supported"),
+            ));
+        }
+
+        *proxy = merged;
+    }
+
+    Ok(())
+}
+
+/// Puts the proxies that didn't join a group into one of their own under the
+/// default load balancing group, so replicas in other clusters can join it.
+/// frp only groups tcp, http and https proxies.
+pub fn apply_default_lb_group(options: &Options, proxies: &mut [Proxy]) {
+    let Some(default) = options.default_lb_group.as_ref() else {
+        return;
+    };
+
+    for proxy in proxies
+        .iter_mut()
+        .filter(|proxy| matches!(proxy.type_.as_str(), "tcp" | "http" | "https"))
+        .filter(|proxy| proxy.load_balancer.is_none())
+    {
+        proxy.load_balancer = Some(LoadBalancer {
+            group: format!("{}-{}", default.group, proxy.name),
+            group_key: default.group_key.clone(),
+        });
+    }
+}
+
+/// Replaces the condition of the same type, keeping its transition time when
+/// the status didn't change.
+pub fn set_condition(conditions: &mut Vec<Condition>, mut condition: Condition) {
+    match conditions.iter_mut().find(|c| c.type_ == condition.type_) {
+        Some(existing) => {
+            if existing.status == condition.status {
+                condition.last_transition_time = existing.last_transition_time.clone();
+            }
+            *existing = condition;
+        }
+        None => conditions.push(condition),
+    }
+}
+
+/// Copies the labels and annotations selected in the options into frp proxy metadatas.
+pub fn proxy_metadatas<K: Resource>(
+    obj: &K,
+    options: &Options,
+) -> Option<BTreeMap<String, String>> {
+    let meta = obj.meta();
+
+    let metadatas: BTreeMap<String, String> = [
+        (&meta.labels, &options.metadata_labels),
+        (&meta.annotations, &options.metadata_annotations),
+    ]
+    .into_iter()
+    .flat_map(|(values, keys)| {
+        keys.iter().filter_map(move |key| {
+            values
+                .as_ref()
+                .and_then(|values| values.get(key))
+                .map(|value| (key.to_owned(), value.to_owned()))
+        })
+    })
+    .collect();
+
+    Some(metadatas).filter(|metadatas| !metadatas.is_empty())
+}
+
+/// Hashes everything a reconcile depends on, leaving out the status and the
+/// annotations the operator writes itself so those don't trigger a reload.
+pub fn reconcile_trigger<K: Serialize>(obj: &K) -> Option<u64> {
+    let mut value = serde_json::to_value(obj).ok()?;
+
+    if let Some(obj) = value.as_object_mut() {
+        obj.remove("status");
+    }
+
+    if let Some(meta) = value.get_mut("metadata").and_then(|m| m.as_object_mut()) {
+        meta.remove("resourceVersion");
+        meta.remove("managedFields");
+
+        if let Some(annotations) = meta.get_mut("annotations").and_then(|a| a.as_object_mut()) {
+            annotations.remove(annotations::STATUS);
+            annotations.remove(annotations::VISITORS);
+            annotations.remove(annotations::ALLOCATED_PORTS);
+            // expires-at stays, removing it is how an expired tunnel is restarted
+        }
+    }
 
-pub async fn run(config: ClientConfig) -> Result<(), Error> {
+    let mut hasher = DefaultHasher::new();
+    value.to_string().hash(&mut hasher);
+    Some(hasher.finish())
+}
+
+/// Requeues every object of a namespace whose labels changed, so the
+/// namespace selector applies without waiting for the objects to change.
+pub fn watch_namespaces<K>(
+    controller: Controller<K>,
+    ctx: &Context,
+    store: Store<K>,
+) -> Controller<K>
+where
+    K: Resource<DynamicType = ()> + Clone + DeserializeOwned + Debug + Send + Sync + 'static,
+{
+    if ctx.options.namespace_selector.is_empty() {
+        return controller;
+    }
+
+    let namespace_api: Api<Namespace> = Api::all(ctx.client.clone());
+    let stream = watcher(namespace_api, watcher::Config::default())
+        .default_backoff()
+        .touched_objects()
+        .predicate_filter(predicates::labels);
+
+    controller.watches_stream(stream, move |namespace| {
+        store
+            .state()
+            .into_iter()
+            .filter(|obj| obj.namespace() == Some(namespace.name_any()))
+            .map(|obj| ObjectRef::from_obj(obj.as_ref()))
+            .collect::<Vec<_>>()
+    })
+}
+
+/// Removes the proxy files of Ingresses and Services deleted, or moved out of
+/// the watched namespaces and selectors, while the operator wasn't running,
+/// before frpc loads them.
+async fn remove_orphaned_proxy_files(ctx: &Context) -> Result<(), Error> {
+    let ingress_api: Api<Ingress> = Api::all(ctx.client.clone());
+    let service_api: Api<Service> = Api::all(ctx.client.clone());
+
+    let mut owned = HashSet::new();
+    for ing in ingress_api.list(&ListParams::default()).await? {
+        if ingress::is_frp_ingress(&ing)
+            && Options::is_selected(&ctx.options.ingress_selector, ing.labels())
+            && ctx
+                .is_watched_namespace(&ing.namespace().unwrap_or("default".to_string()))
+                .await
+        {
+            owned.insert(ing.name_any());
+        }
+    }
+    for svc in service_api.list(&ListParams::default()).await? {
+        if service::is_frp_service(&svc)
+            && Options::is_selected(&ctx.options.service_selector, svc.labels())
+            && ctx
+                .is_watched_namespace(&svc.namespace().unwrap_or("default".to_string()))
+                .await
+        {
+            owned.insert(svc.name_any());
+        }
+    }
+
+    for name in frpc::config_proxy_names().await? {
+        if !owned.contains(&name) {
+            info!("removing orphaned proxy file of {name}");
+            frpc::remove_config_proxy_file(&name).await?;
+        }
+    }
+
+    Ok(())
+}
+
+const FRPC_MIN_BACKOFF: Duration = Duration::from_secs(1);
+const FRPC_MAX_BACKOFF: Duration = Duration::from_secs(300);
+/// How long frpc has to stay up for the backoff to start over.
+const FRPC_STABLE_RUN: Duration = Duration::from_secs(60);
+/// How often an unmanaged frpc is checked for the proxies pushed to it.
+const UNMANAGED_RESYNC: Duration = Duration::from_secs(30);
+
+/// Keeps the proxies pushed to an unmanaged frpc, retrying with exponential
+/// backoff while it's unreachable and pushing again whenever it lost them,
+/// e.g. after it restarted with its own config.
+async fn sync_unmanaged_frpc(options: &Options, config: ClientConfig) {
+    if let Err(err) = frpc::write_config_to_file(config, options.strict_config, true).await {
+        error!("failed to write frpc config: {err}");
+        return;
+    }
+
+    // the first push drops managed proxies left over from a previous run
+    let mut synced = false;
+    let mut backoff = FRPC_MIN_BACKOFF;
+
+    loop {
+        let pushed = match frpc::admin::missing_proxies().await {
+            Ok(missing) if synced && missing.is_empty() => Ok(()),
+            Ok(missing) => {
+                if synced {
+                    info!("frpc lost proxies {}, pushing", missing.join(", "));
+                }
+                frpc::admin::push(options.strict_config).await
+            }
+            Err(err) => Err(err),
+        };
+
+        match pushed {
+            Ok(()) => {
+                synced = true;
+                backoff = FRPC_MIN_BACKOFF;
+                tokio::time::sleep(UNMANAGED_RESYNC).await;
+            }
+            Err(err) => {
+                warn!(
+                    "failed to push config to frpc, retry in {}s: {err}",
+                    backoff.as_secs()
+                );
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(FRPC_MAX_BACKOFF);
+            }
+        }
+    }
+}
+
+/// Keeps frpc running, restarting it with exponential backoff whenever it
+/// exits and recording every restart as an event on the operator pod.
+async fn supervise_frpc(ctx: Arc<Context>, config: ClientConfig) {
+    let options = &ctx.options;
+
+    if options.unmanaged {
+        return sync_unmanaged_frpc(options, config).await;
+    }
+
+    let mut restarts: u32 = 0;
+    let mut backoff = FRPC_MIN_BACKOFF;
+    let mut server = 0;
+
+    loop {
+        let mut config = config.clone();
+        if let Some(server_addr) = options.server_addrs.get(server) {
+            config.server_addr = server_addr.clone();
+        }
+
+        let started = Instant::now();
+        let reason = match frpc::run(config, options.strict_config).await {
+            Ok(()) => "frpc exited".to_string(),
+            Err(err) => err.to_string(),
+        };
+
+        // frpc exits early when it can't log in, the next frps is tried then,
+        // after a stable run the preferred one is tried again
+        if started.elapsed() >= FRPC_STABLE_RUN {
+            backoff = FRPC_MIN_BACKOFF;
+            server = 0;
+        } else if !options.server_addrs.is_empty() {
+            server = (server + 1) % options.server_addrs.len();
+        }
+        restarts += 1;
+
+        let mut note = format!("{reason}, restart {restarts} in {}s", backoff.as_secs());
+        if options.server_addrs.len() > 1 {
+            note.push_str(&format!(" with frps {}", options.server_addrs[server]));
+        }
+        warn!("{note}");
+
+        if let Some((ns, name)) = options
+            .pod_namespace
+            .as_ref()
+            .zip(options.pod_name.as_ref())
+        {
+            let pod_api: Api<Pod> = Api::namespaced(ctx.client.clone(), ns);
+            let published = match pod_api.get(name).await {
+                Ok(pod) => {
+                    publish_event(&ctx.client, &pod, EventType::Warning, "FrpcRestarted", note)
+                        .await
+                }
+                Err(err) => Err(err.into()),
+            };
+            if let Err(err) = published {
+                warn!("failed to publish frpc restart event: {err}");
+            }
+        }
+
+        tokio::time::sleep(backoff).await;
+        backoff = (backoff * 2).min(FRPC_MAX_BACKOFF);
+    }
+}
+
+pub async fn run(config: ClientConfig, options: Options) -> Result<(), Error> {
     let client = kube::Client::try_default().await?;
 
-    let ctx = Arc::new(Context { client });
+    let (namespaces, namespaces_writer) = reflector::store();
+    let (ingresses, ingresses_writer) = reflector::store();
+    let (services, services_writer) = reflector::store();
+    let (endpoint_slices, endpoint_slices_writer) = reflector::store();
+
+    let ctx = Arc::new(Context {
+        client: client.clone(),
+        options,
+        ingresses,
+        services,
+        endpoint_slices,
+        namespaces,
+    });
+
+    // namespaces are only cached when they are selected by label
+    if !ctx.options.namespace_selector.is_empty() {
+        let namespace_api: Api<Namespace> = Api::all(client.clone());
+        let stream = reflector::reflector(
+            namespaces_writer,
+            watcher(namespace_api, watcher::Config::default()),
+        )
+        .default_backoff()
+        .touched_objects();
+        tokio::spawn(stream.for_each(|_| async {}));
+    }
 
-    let frpc_fut = frpc::run(config);
+    // frpc isn't running yet, it starts from the cleaned up files without a reload
+    if let Err(err) = remove_orphaned_proxy_files(&ctx).await {
+        warn!("failed to remove orphaned proxy files: {err}");
+    }
 
-    let ingress_fut = ingress::run(ctx.clone());
+    let frpc_fut = supervise_frpc(ctx.clone(), config);
 
-    let service_fut = service::run(ctx.clone());
+    let ingress_fut = ingress::run(ctx.clone(), ingresses_writer);
 
-    let _ = futures_util::join!(frpc_fut, ingress_fut, service_fut);
+    let service_fut = service::run(ctx.clone(), services_writer, endpoint_slices_writer);
+
+    let stats_fut = stats::run(ctx.clone());
+
+    let _ = futures_util::join!(frpc_fut, ingress_fut, service_fut, stats_fut);
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn proxy(name: &str, type_: &str, load_balancer: Option<LoadBalancer>) -> Proxy {
+        Proxy {
+            name: name.to_string(),
+            type_: type_.to_string(),
+            load_balancer,
+            ..Proxy::default()
+        }
+    }
+
+    #[test]
+    fn apply_default_lb_group_leaves_grouped_and_ungroupable_proxies_alone() {
+        let options = Options {
+            default_lb_group: Some(LoadBalancer {
+                group: "edge".to_string(),
+                group_key: "edge-key".to_string(),
+            }),
+            ..Options::default()
+        };
+        let canary = LoadBalancer {
+            group: "canary-shop".to_string(),
+            group_key: "canary-key".to_string(),
+        };
+
+        let mut proxies = vec![
+            proxy("svc-web-http", "http", None),
+            proxy("svc-web-canary", "http", Some(canary.clone())),
+            proxy("svc-dns-53", "udp", None),
+        ];
+        apply_default_lb_group(&options, &mut proxies);
+
+        assert_eq!(
+            proxies[0].load_balancer,
+            Some(LoadBalancer {
+                group: "edge-svc-web-http".to_string(),
+                group_key: "edge-key".to_string(),
+            })
+        );
+        assert_eq!(proxies[1].load_balancer, Some(canary));
+        assert_eq!(proxies[2].load_balancer, None);
+    }
+
+    #[test]
+    fn apply_default_lb_group_does_nothing_without_a_default() {
+        let mut proxies = vec![proxy("svc-web-http", "http", None)];
+        apply_default_lb_group(&Options::default(), &mut proxies);
+
+        assert_eq!(proxies[0].load_balancer, None);
+    }
+}
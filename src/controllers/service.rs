@@ -1,27 +1,268 @@
-use std::{sync::Arc, time::Duration};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    net::IpAddr,
+    sync::Arc,
+    time::Duration,
+};
 
 use futures_util::StreamExt;
-use k8s_openapi::api::core::v1::{LoadBalancerIngress, LoadBalancerStatus, Service, ServiceStatus};
+use k8s_openapi::{
+    api::{
+        core::v1::{
+            LoadBalancerIngress, LoadBalancerStatus, PortStatus, Service, ServicePort,
+            ServiceStatus,
+        },
+        discovery::v1::{Endpoint, EndpointSlice},
+    },
+    apimachinery::pkg::{
+        apis::meta::v1::{Condition, Time},
+        util::intstr::IntOrString,
+    },
+    chrono::{self, DateTime, Utc},
+};
 use kube::{
     api::{Patch, PatchParams},
-    runtime::{controller::Action, finalizer, reflector, watcher, Controller, WatchStreamExt},
+    runtime::{
+        controller::{self, Action},
+        events::EventType,
+        finalizer, reflector,
+        reflector::{store::Writer, ObjectRef},
+        watcher, Controller, WatchStreamExt,
+    },
     Api, ResourceExt,
 };
 use log::{error, info, warn};
+use serde_json::json;
+
+use anyhow::anyhow;
 
 use crate::{
-    context::Context,
+    annotations::{self, LocalPortMode},
+    context::{Context, Options},
+    controllers::{
+        apply_default_lb_group, apply_proxy_snippet, proxy_metadatas, proxy_transport,
+        publish_event, reconcile_trigger, secret_value, set_condition, watch_namespaces,
+    },
     error::Error,
     frpc::{
         self,
-        config::{Proxy, ProxyConfig},
+        config::{HealthCheck, LoadBalancer, Proxy, ProxyConfig, Visitor, VisitorConfig},
     },
     OPERATOR_MANAGER,
 };
 
+const SERVICE_NAME_LABEL: &str = "kubernetes.io/service-name";
+
 pub const SERVICE_FINALIZER: &str = "frp-operator.io/service-finalizer";
 
-pub async fn proxy_from_service(svc: &Service) -> Result<ProxyConfig, Error> {
+/// Maps a Service port onto the frp proxy type tunneling it, preferring the
+/// application protocol so HTTP services are routed as vhosts.
+pub fn proxy_type(port: &ServicePort) -> Result<&'static str, Error> {
+    let protocol = port.protocol.as_deref().unwrap_or("TCP");
+
+    match (protocol, port.app_protocol.as_deref()) {
+        ("TCP", Some("http" | "kubernetes.io/h2c" | "kubernetes.io/ws")) => Ok("http"),
+        ("TCP", Some("https" | "kubernetes.io/wss")) => Ok("https"),
+        ("TCP", _) => Ok("tcp"),
+        ("UDP", _) => Ok("udp"),
+        (protocol, _) => Err(anyhow!("unsupported service port protocol {protocol}").into()),
+    }
+}
+
+fn target_port(port: &ServicePort, slices: &[EndpointSlice]) -> Result<u16, Error> {
+    match port.target_port.as_ref() {
+        None => Ok(port.port as u16),
+        Some(IntOrString::Int(target_port)) => Ok(*target_port as u16),
+        // named target ports are resolved per pod, endpoint slices carry the numbers
+        Some(IntOrString::String(target_port)) => slices
+            .iter()
+            .flat_map(|slice| slice.ports.iter().flatten())
+            .find(|slice_port| slice_port.name == port.name)
+            .and_then(|slice_port| slice_port.port)
+            .map(|port| port as u16)
+            .ok_or_else(|| anyhow!("failed to resolve target port {target_port}").into()),
+    }
+}
+
+fn endpoint_ready(endpoint: &Endpoint) -> bool {
+    endpoint
+        .conditions
+        .as_ref()
+        .and_then(|conditions| conditions.ready)
+        .unwrap_or(true)
+}
+
+/// Returns the address and port of every ready endpoint serving the given Service port.
+fn ready_endpoints(port: &ServicePort, slices: &[EndpointSlice]) -> Vec<(String, u16)> {
+    let mut endpoints: Vec<(String, u16)> = slices
+        .iter()
+        .filter_map(|slice| {
+            slice
+                .ports
+                .iter()
+                .flatten()
+                .find(|slice_port| slice_port.name == port.name)
+                .and_then(|slice_port| slice_port.port)
+                .map(|target_port| (slice, target_port as u16))
+        })
+        .flat_map(|(slice, target_port)| {
+            slice
+                .endpoints
+                .iter()
+                .filter(|endpoint| endpoint_ready(endpoint))
+                .flat_map(|endpoint| endpoint.addresses.iter())
+                .map(move |address| (address.to_owned(), target_port))
+        })
+        .collect();
+
+    endpoints.sort();
+    endpoints.dedup();
+    endpoints
+}
+
+/// Returns the EndpointSlices of a Service from the store, waiting for its
+/// first sync so an empty store isn't taken for a Service without endpoints.
+pub async fn endpoint_slices(svc: &Service, ctx: &Context) -> Result<Vec<EndpointSlice>, Error> {
+    let ns = svc.namespace().unwrap_or("default".to_string());
+    let name = svc.name_any();
+
+    ctx.endpoint_slices
+        .wait_until_ready()
+        .await
+        .map_err(|err| anyhow!("endpoint slice store is gone: {err}"))?;
+
+    // a dual-stack Service has a slice per family, only the primary one is
+    // tunneled so every pod is proxied once
+    let primary_family = svc
+        .spec
+        .as_ref()
+        .and_then(|spec| spec.ip_families.as_ref())
+        .and_then(|families| families.first());
+
+    Ok(ctx
+        .endpoint_slices
+        .state()
+        .into_iter()
+        .filter(|slice| {
+            slice.namespace().as_ref() == Some(&ns)
+                && slice.labels().get(SERVICE_NAME_LABEL) == Some(&name)
+        })
+        .filter(|slice| {
+            slice.address_type == "FQDN"
+                || primary_family.is_none_or(|family| *family == slice.address_type)
+        })
+        .map(|slice| (*slice).clone())
+        .collect())
+}
+
+pub fn has_ready_endpoints(slices: &[EndpointSlice]) -> bool {
+    slices
+        .iter()
+        .flat_map(|slice| slice.endpoints.iter())
+        .any(endpoint_ready)
+}
+
+/// Builds the frp health check from the `health-check` annotations, so frps
+/// stops routing to a backend that no longer accepts connections.
+fn health_check(annotations: &BTreeMap<String, String>) -> Result<Option<HealthCheck>, Error> {
+    let Some(type_) = annotations::health_check(annotations)? else {
+        return Ok(None);
+    };
+
+    Ok(Some(HealthCheck {
+        type_,
+        timeout_seconds: annotations::positive_integer(
+            annotations,
+            annotations::HEALTH_CHECK_TIMEOUT,
+        )?,
+        max_failed: annotations::positive_integer(
+            annotations,
+            annotations::HEALTH_CHECK_MAX_FAILED,
+        )?,
+        interval_seconds: annotations::positive_integer(
+            annotations,
+            annotations::HEALTH_CHECK_INTERVAL,
+        )?,
+    }))
+}
+
+/// frp can't put udp proxies in a group, so every endpoint of a udp port
+/// would bind the same remote port.
+fn check_endpoints_port(port: &ServicePort) -> Result<(), Error> {
+    if port.protocol.as_deref() == Some("UDP") {
+        return Err(Error::InvalidAnnotation(
+            annotations::LOCAL_PORT_MODE.to_string(),
+            format!(
+                "endpoints can't tunnel udp port {}, frp can't group udp proxies",
+                port.port
+            ),
+        ));
+    }
+
+    Ok(())
+}
+
+/// Secret proxies are reached through one visitor each, the endpoints of a
+/// port would need a visitor per pod.
+fn check_endpoints_secret_refs(annotations: &BTreeMap<String, String>) -> Result<(), Error> {
+    for annotation in [annotations::STCP_SECRET_REF, annotations::SUDP_SECRET_REF] {
+        if annotations.contains_key(annotation) {
+            return Err(Error::InvalidAnnotation(
+                annotations::LOCAL_PORT_MODE.to_string(),
+                format!("endpoints can't be combined with {annotation}"),
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// Headless services have no virtual port, traffic has to go to the pods directly.
+fn is_headless(svc: &Service) -> bool {
+    svc.spec
+        .as_ref()
+        .and_then(|spec| spec.cluster_ip.as_deref())
+        == Some("None")
+}
+
+/// Picks the port proxies dial, rejecting target ports on a Service with a
+/// cluster ip, its DNS name resolves to that ip which only serves the Service ports.
+fn local_port_mode(svc: &Service) -> Result<LocalPortMode, Error> {
+    match annotations::local_port_mode(svc.annotations())? {
+        // an external name has no pods, its port is the one the Service lists
+        _ if external_name(svc).is_some() => Ok(LocalPortMode::Service),
+        Some(LocalPortMode::Target) if !is_headless(svc) => Err(Error::InvalidAnnotation(
+            annotations::LOCAL_PORT_MODE.to_string(),
+            "target needs a headless service, use endpoints to dial the pods".to_string(),
+        )),
+        Some(mode) => Ok(mode),
+        None if is_headless(svc) => Ok(LocalPortMode::Target),
+        None => Ok(LocalPortMode::Service),
+    }
+}
+
+/// Names the proxy of a Service port `svc-<name>-<port>`, unless the
+/// `proxy-name-template` annotation asks for another convention.
+fn proxy_name(svc: &Service, port: &ServicePort) -> Result<String, Error> {
+    let port = port.name.clone().unwrap_or(port.port.to_string());
+
+    let Some(template) = annotations::proxy_name_template(svc.annotations())? else {
+        return Ok(format!("svc-{}-{port}", svc.name_any()));
+    };
+
+    Ok(template
+        .replace("{{ns}}", &svc.namespace().unwrap_or("default".to_string()))
+        .replace("{{name}}", &svc.name_any())
+        .replace("{{port}}", &port))
+}
+
+pub async fn proxy_from_service(
+    svc: &Service,
+    ctx: &Context,
+    slices: &[EndpointSlice],
+    skipped: &mut Vec<String>,
+) -> Result<ProxyConfig, Error> {
+    let options = &ctx.options;
     let svc_name = svc.name_any();
     let mut config = ProxyConfig {
         name: svc_name.clone(),
@@ -29,51 +270,813 @@ pub async fn proxy_from_service(svc: &Service) -> Result<ProxyConfig, Error> {
     };
 
     let ns = svc.namespace().clone().unwrap_or("default".to_string());
+    let metadatas = proxy_metadatas(svc, options);
+    let transport = proxy_transport(svc.annotations())?;
+    let health_check = health_check(svc.annotations())?;
+    let lb_group = annotations::lb_group(svc.annotations())?;
+    let http_domains = annotations::http_domains(svc.annotations())?;
+    let subdomain = annotations::subdomain(svc.annotations())?;
 
-    for port in svc
+    let mut secret_keys = HashMap::new();
+    for (type_, annotation) in [
+        ("tcp", annotations::STCP_SECRET_REF),
+        ("udp", annotations::SUDP_SECRET_REF),
+    ] {
+        if let Some((name, key)) = annotations::secret_ref(svc.annotations(), annotation)? {
+            secret_keys.insert(type_, secret_value(ctx, &ns, &name, &key).await?);
+        }
+    }
+
+    let local_port_mode = local_port_mode(svc)?;
+    if local_port_mode == LocalPortMode::Endpoints {
+        check_endpoints_secret_refs(svc.annotations())?;
+    }
+
+    let mut ports: Vec<&ServicePort> = svc
         .spec
         .as_ref()
         .and_then(|spec| spec.ports.as_ref())
         .into_iter()
         .flatten()
+        .collect();
+
+    if let Some(expose_ports) = annotations::expose_ports(svc.annotations()) {
+        let selected = |port: &ServicePort, key: &String| {
+            port.name.as_ref() == Some(key) || port.port.to_string() == *key
+        };
+
+        if let Some(unknown) = expose_ports
+            .iter()
+            .find(|key| !ports.iter().any(|port| selected(port, key)))
+        {
+            return Err(Error::InvalidAnnotation(
+                annotations::EXPOSE_PORTS.to_string(),
+                format!("service has no port {unknown}"),
+            ));
+        }
+
+        ports.retain(|port| expose_ports.iter().any(|key| selected(port, key)));
+    }
+
+    // frp has no sctp proxies, one such port mustn't keep the others from being tunneled
+    let (sctp_ports, tunneled_ports): (Vec<&ServicePort>, Vec<&ServicePort>) = ports
+        .into_iter()
+        .partition(|port| port.protocol.as_deref() == Some("SCTP"));
+    let ports = tunneled_ports;
+
+    skipped.extend(
+        sctp_ports
+            .iter()
+            .map(|port| port.name.clone().unwrap_or(port.port.to_string())),
+    );
+
+    if let Some(unknown) = annotations::port_map(svc.annotations())?
+        .keys()
+        .find(|key| {
+            !ports
+                .iter()
+                .any(|port| port.name.as_ref() == Some(key) || port.port.to_string() == **key)
+        })
     {
-        let name = format!(
-            "svc-{svc_name}-{}",
-            port.name.clone().unwrap_or(port.port.to_string())
-        );
+        return Err(Error::InvalidAnnotation(
+            annotations::PORT_MAP.to_string(),
+            format!("service has no exposed port {unknown}"),
+        ));
+    }
+
+    for port in ports.iter().copied() {
+        let name = proxy_name(svc, port)?;
+        if config.proxies.iter().any(|proxy| proxy.name == name) {
+            return Err(Error::InvalidAnnotation(
+                annotations::PROXY_NAME_TEMPLATE.to_string(),
+                format!("renders {name} for several ports, include {{{{port}}}}"),
+            ));
+        }
+
+        if local_port_mode == LocalPortMode::Endpoints {
+            check_endpoints_port(port)?;
+        }
+
+        let type_ = proxy_type(port)?;
+        let secret_key = secret_keys
+            .get(if type_ == "udp" { "udp" } else { "tcp" })
+            .cloned();
+        let type_ = match (type_, secret_key.as_ref(), http_domains.as_ref()) {
+            ("udp", Some(_), _) => "sudp",
+            (_, Some(_), None) => "stcp",
+            (type_, None, None) => type_,
+            ("udp", _, Some(_)) | (_, Some(_), Some(_)) => {
+                return Err(Error::InvalidAnnotation(
+                    annotations::HTTP_DOMAINS.to_string(),
+                    format!(
+                        "port {} can't be routed by domain, select tcp ports with {}",
+                        port.port,
+                        annotations::EXPOSE_PORTS
+                    ),
+                ));
+            }
+            // tls is passed through to the backend, frps routes it by SNI
+            ("https", None, Some(_)) => "https",
+            (_, None, Some(_)) if port.name.as_deref() == Some("https") || port.port == 443 => {
+                "https"
+            }
+            (_, None, Some(_)) => "http",
+        };
+        let vhost = matches!(type_, "http" | "https");
+
+        // frps only groups proxies bound to the same remote port, so every
+        // port of a multi-port Service gets a group of its own
+        let load_balancer = match lb_group.as_ref() {
+            Some(_) if !matches!(type_, "tcp" | "http" | "https") => {
+                return Err(Error::InvalidAnnotation(
+                    annotations::LB_GROUP.to_string(),
+                    format!("{type_} proxies can't join a load balancing group"),
+                ));
+            }
+            Some((group, group_key)) => Some(LoadBalancer {
+                group: if ports.len() == 1 {
+                    group.clone()
+                } else {
+                    format!(
+                        "{group}-{}",
+                        port.name.clone().unwrap_or(port.port.to_string())
+                    )
+                },
+                group_key: group_key.clone(),
+            }),
+            None => None,
+        };
 
-        config.proxies.push(Proxy {
+        let proxy = Proxy {
             name,
-            type_: port
-                .protocol
-                .as_ref()
-                .map(|protocol| protocol.to_lowercase())
-                .unwrap_or("tcp".to_string()),
-            local_ip: Some(format!("{svc_name}.{ns}.svc.cluster.local")),
-            local_port: Some(port.port as u16),
-            remote_port: Some(port.port as u16),
+            type_: type_.to_string(),
+            local_ip: Some(match external_name(svc) {
+                Some(external_name) => external_name.to_string(),
+                None => format!("{svc_name}.{ns}.svc.cluster.local"),
+            }),
+            local_port: Some(match local_port_mode {
+                LocalPortMode::Service | LocalPortMode::Endpoints => port.port as u16,
+                LocalPortMode::Target => target_port(port, slices)?,
+            }),
+            remote_port: if vhost || secret_key.is_some() {
+                None
+            } else {
+                Some(
+                    annotations::remote_port(
+                        svc.annotations(),
+                        port.name.as_deref(),
+                        port.port,
+                        port.protocol.as_deref().unwrap_or("TCP"),
+                        ports.len() == 1,
+                    )?
+                    .unwrap_or(port.port as u16),
+                )
+            },
+            custom_domains: http_domains.clone().filter(|_| vhost),
+            subdomain: match (vhost, subdomain.as_ref(), http_domains.as_ref()) {
+                (false, _, _) => None,
+                (true, Some(subdomain), _) => Some(subdomain.clone()),
+                // qualified so Services of the same name in other namespaces, and
+                // the other ports of this one, get subdomains of their own
+                (true, None, None) if ports.len() == 1 => Some(format!("{svc_name}-{ns}")),
+                (true, None, None) => Some(format!(
+                    "{svc_name}-{}-{ns}",
+                    port.name.clone().unwrap_or(port.port.to_string())
+                )),
+                (true, None, Some(_)) => None,
+            },
+            // a tcp connect says nothing about a udp backend
+            health_check: if matches!(type_, "udp" | "sudp") {
+                None
+            } else {
+                health_check.clone()
+            },
+            secret_key,
+            load_balancer,
+            transport: transport.clone(),
+            metadatas: metadatas.clone(),
             ..Proxy::default()
-        });
+        };
+
+        if local_port_mode != LocalPortMode::Endpoints {
+            config.proxies.push(proxy);
+            continue;
+        }
+
+        // every ready endpoint joins one group so frps balances across the pods
+        for (i, (address, target_port)) in ready_endpoints(port, slices).into_iter().enumerate() {
+            config.proxies.push(Proxy {
+                name: format!("{}-{i}", proxy.name),
+                local_ip: Some(address),
+                local_port: Some(target_port),
+                load_balancer: proxy.load_balancer.clone().or(Some(LoadBalancer {
+                    group: format!("{ns}-{}", proxy.name),
+                    group_key: options.group_key(&format!("{ns}-{}", proxy.name)),
+                })),
+                ..proxy.clone()
+            });
+        }
+    }
+
+    // validated before the default group puts every proxy in a group of its own
+    validate_remote_ports(&config)?;
+    validate_subdomains(&config)?;
+
+    apply_default_lb_group(options, &mut config.proxies);
+    apply_proxy_snippet(svc.annotations(), &mut config.proxies)?;
+
+    if subdomain.is_some()
+        && !config
+            .proxies
+            .iter()
+            .any(|proxy| matches!(proxy.type_.as_str(), "http" | "https"))
+    {
+        return Err(Error::InvalidAnnotation(
+            annotations::SUBDOMAIN.to_string(),
+            "service has no http or https port".to_string(),
+        ));
+    }
+
+    if http_domains.is_some() {
+        for type_ in ["http", "https"] {
+            // members of a group serve the same port
+            let mut ports: Vec<&String> = config
+                .proxies
+                .iter()
+                .filter(|proxy| proxy.type_ == type_)
+                .map(|proxy| match proxy.load_balancer.as_ref() {
+                    Some(load_balancer) => &load_balancer.group,
+                    None => &proxy.name,
+                })
+                .collect();
+            ports.dedup();
+            let ports = ports.len();
+
+            if ports > 1 {
+                return Err(Error::InvalidAnnotation(
+                    annotations::HTTP_DOMAINS.to_string(),
+                    format!("the domains can route {type_} to one port only, found {ports}"),
+                ));
+            }
+        }
     }
 
-    return Ok(config);
+    Ok(config)
 }
 
-async fn reconcile(obj: Arc<Service>, ctx: Arc<Context>) -> Result<Action, Error> {
-    if obj
+/// Checks the annotations of a Service that don't need its secrets or
+/// endpoints, so obvious mistakes are rejected at admission.
+pub fn validate_service(svc: &Service) -> Result<(), Error> {
+    let annotations = svc.annotations();
+
+    proxy_transport(annotations)?;
+    health_check(annotations)?;
+    annotations::lb_group(annotations)?;
+    annotations::http_domains(annotations)?;
+    annotations::subdomain(annotations)?;
+    annotations::hostname(annotations)?;
+    let local_port_mode = local_port_mode(svc)?;
+    if local_port_mode == LocalPortMode::Endpoints {
+        check_endpoints_secret_refs(annotations)?;
+    }
+    annotations::proxy_snippet(annotations)?;
+    annotations::expire_after(annotations)?;
+    for annotation in [annotations::STCP_SECRET_REF, annotations::SUDP_SECRET_REF] {
+        annotations::secret_ref(annotations, annotation)?;
+    }
+
+    let ports: Vec<&ServicePort> = svc
+        .spec
+        .as_ref()
+        .and_then(|spec| spec.ports.as_ref())
+        .into_iter()
+        .flatten()
+        .collect();
+
+    if let Some(unknown) = annotations::port_map(annotations)?.keys().find(|key| {
+        !ports
+            .iter()
+            .any(|port| port.name.as_ref() == Some(key) || port.port.to_string() == **key)
+    }) {
+        return Err(Error::InvalidAnnotation(
+            annotations::PORT_MAP.to_string(),
+            format!("service has no port {unknown}"),
+        ));
+    }
+
+    let mut names = HashSet::new();
+    for port in ports.iter() {
+        annotations::remote_port(
+            annotations,
+            port.name.as_deref(),
+            port.port,
+            port.protocol.as_deref().unwrap_or("TCP"),
+            ports.len() == 1,
+        )?;
+
+        if local_port_mode == LocalPortMode::Endpoints {
+            check_endpoints_port(port)?;
+        }
+
+        let name = proxy_name(svc, port)?;
+        if !names.insert(name.clone()) {
+            return Err(Error::InvalidAnnotation(
+                annotations::PROXY_NAME_TEMPLATE.to_string(),
+                format!("renders {name} for several ports, include {{{{port}}}}"),
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+fn same_group(a: &Proxy, b: &Proxy) -> bool {
+    matches!(
+        (a.load_balancer.as_ref(), b.load_balancer.as_ref()),
+        (Some(a), Some(b)) if a.group == b.group
+    )
+}
+
+/// frps binds tcp and udp remote ports separately, so a port may only be shared
+/// across protocols, e.g. DNS serving 53 over both, or by the members of one group.
+fn validate_remote_ports(config: &ProxyConfig) -> Result<(), Error> {
+    let mut bound: HashMap<(&str, u16), &Proxy> = HashMap::new();
+
+    for proxy in config.proxies.iter() {
+        let Some(remote_port) = proxy.remote_port else {
+            continue;
+        };
+
+        let Some(other) = bound.insert((proxy.type_.as_str(), remote_port), proxy) else {
+            continue;
+        };
+
+        if !same_group(other, proxy) {
+            return Err(anyhow!(
+                "proxies {} and {} both bind {} remote port {remote_port}",
+                other.name,
+                proxy.name,
+                proxy.type_
+            )
+            .into());
+        }
+    }
+
+    Ok(())
+}
+
+/// frps routes a subdomain of a vhost type to a single proxy, or to the
+/// members of one group.
+fn validate_subdomains(config: &ProxyConfig) -> Result<(), Error> {
+    let mut routed: HashMap<(&str, &str), &Proxy> = HashMap::new();
+
+    for proxy in config.proxies.iter() {
+        let Some(subdomain) = proxy.subdomain.as_deref() else {
+            continue;
+        };
+
+        let Some(other) = routed.insert((proxy.type_.as_str(), subdomain), proxy) else {
+            continue;
+        };
+
+        if !same_group(other, proxy) {
+            return Err(Error::InvalidAnnotation(
+                annotations::SUBDOMAIN.to_string(),
+                format!(
+                    "proxies {} and {} both route {} subdomain {subdomain}, select one port with {}",
+                    other.name,
+                    proxy.name,
+                    proxy.type_,
+                    annotations::EXPOSE_PORTS
+                ),
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// Lists the remote ports of the config already bound by proxies of other
+/// resources, which would make frpc reject the reload for every tunnel.
+async fn remote_port_conflicts(config: &ProxyConfig) -> Result<Vec<String>, Error> {
+    let others = frpc::read_config_proxies_from_files().await?;
+    let mut conflicts = vec![];
+
+    for proxy in config.proxies.iter() {
+        let Some(remote_port) = proxy.remote_port else {
+            continue;
+        };
+
+        let conflict = others
+            .iter()
+            .filter(|other| other.name != config.name)
+            .flat_map(|other| other.proxies.iter())
+            .find(|other| {
+                other.remote_port == Some(remote_port)
+                    && other.type_ == proxy.type_
+                    && (other.load_balancer.is_none() || other.load_balancer != proxy.load_balancer)
+            });
+
+        if let Some(other) = conflict {
+            conflicts.push(format!(
+                "{} remote port {remote_port} is already bound by proxy {}",
+                proxy.type_, other.name
+            ));
+        }
+    }
+
+    Ok(conflicts)
+}
+
+fn status_ports(config: &ProxyConfig, options: &Options) -> Vec<PortStatus> {
+    let mut ports: Vec<PortStatus> = vec![];
+
+    for proxy in config.proxies.iter() {
+        let port = match proxy.type_.as_str() {
+            "tcp" | "udp" => proxy.remote_port,
+            "http" => Some(options.vhost_http_port),
+            "https" => Some(options.vhost_https_port),
+            _ => None,
+        };
+
+        let Some(port) = port else {
+            continue;
+        };
+
+        let status = PortStatus {
+            port: port as i32,
+            protocol: if proxy.type_ == "udp" { "UDP" } else { "TCP" }.to_string(),
+            error: None,
+        };
+
+        if !ports.contains(&status) {
+            ports.push(status);
+        }
+    }
+
+    ports
+}
+
+/// Maps `<port>/<protocol>` of every Service port to the frps port serving it,
+/// for the ports where the two differ.
+fn allocated_ports(svc: &Service, config: &ProxyConfig) -> Result<BTreeMap<String, u16>, Error> {
+    let mut allocated = BTreeMap::new();
+
+    for port in svc
         .spec
         .as_ref()
-        .filter(|spec| spec.type_ == Some("LoadBalancer".to_string()))
-        .is_none()
-        || obj
-            .spec
-            .as_ref()
-            .filter(|spec| spec.load_balancer_class == Some("frp".to_string()))
-            .is_none()
+        .and_then(|spec| spec.ports.as_ref())
+        .into_iter()
+        .flatten()
     {
-        return Ok(Action::requeue(Duration::from_secs(3600)));
+        let name = proxy_name(svc, port)?;
+        // endpoints mode names the members of a port `<proxy name>-<index>`
+        let remote_port = config
+            .proxies
+            .iter()
+            .find(|proxy| {
+                proxy.name == name
+                    || proxy
+                        .name
+                        .rsplit_once('-')
+                        .is_some_and(|(prefix, i)| prefix == name && i.parse::<usize>().is_ok())
+            })
+            .and_then(|proxy| proxy.remote_port);
+
+        match remote_port {
+            Some(remote_port) if remote_port as i32 != port.port => {
+                let protocol = port.protocol.as_deref().unwrap_or("TCP");
+                allocated.insert(format!("{}/{protocol}", port.port), remote_port);
+            }
+            _ => {}
+        }
     }
 
+    Ok(allocated)
+}
+
+/// Returns the ip and hostname to publish for a load balancer. The frps
+/// address fills whichever one it is, a given hostname overrides it.
+pub async fn load_balancer_address(hostname: Option<String>) -> (Option<String>, Option<String>) {
+    // an unmanaged frpc may be started without the frps address
+    let Some(config) = frpc::read_config_from_file()
+        .await
+        .ok()
+        .filter(|config| !config.server_addr.is_empty())
+    else {
+        return (None, hostname);
+    };
+
+    let server_addr = frpc::unbracket(&config.server_addr).to_string();
+    if server_addr.parse::<IpAddr>().is_ok() {
+        (Some(server_addr), hostname)
+    } else {
+        (None, hostname.or(Some(server_addr)))
+    }
+}
+
+/// Reports whether frpc has every proxy of the Service running. frpc starts
+/// proxies in the background after a reload, so pending ones are polled for a
+/// few seconds before they're reported as not running.
+async fn load_balancer_condition(proxy_names: &[String], generation: Option<i64>) -> Condition {
+    let mut attempts = 0;
+
+    let (status, reason, message) = loop {
+        attempts += 1;
+
+        let statuses = match frpc::admin::status().await {
+            Ok(statuses) => statuses,
+            Err(err) => break ("Unknown", "AdminApiUnavailable", err.to_string()),
+        };
+
+        let pending = proxy_names.iter().any(|name| {
+            statuses
+                .get(name)
+                .is_none_or(|status| !status.is_running() && status.err.is_empty())
+        });
+        if pending && attempts < 5 {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            continue;
+        }
+
+        let mut rejected = false;
+        let failing: Vec<String> = proxy_names
+            .iter()
+            .filter_map(|name| match statuses.get(name) {
+                Some(status) if status.is_running() => None,
+                Some(status) if !status.err.is_empty() => {
+                    rejected = true;
+                    Some(format!("{name}: {} ({})", status.status, status.err))
+                }
+                Some(status) => Some(format!("{name}: {}", status.status)),
+                None => Some(format!("{name}: not loaded")),
+            })
+            .collect();
+
+        break match (failing.is_empty(), rejected) {
+            (true, _) => (
+                "True",
+                "ProxiesRunning",
+                "all proxies are running".to_string(),
+            ),
+            // frps refused them, e.g. the remote port is taken or auth failed
+            (false, true) => ("False", "ProxiesRejected", failing.join(", ")),
+            (false, false) => ("False", "ProxiesNotRunning", failing.join(", ")),
+        };
+    };
+
+    Condition {
+        type_: "LoadBalancerReady".to_string(),
+        status: status.to_string(),
+        reason: reason.to_string(),
+        message,
+        observed_generation: generation,
+        last_transition_time: Time(Utc::now()),
+    }
+}
+
+/// Renders the visitor side matching the secret proxies of a Service, with the
+/// secret key left as a placeholder, so users know how to reach it.
+pub fn visitors_example(config: &ProxyConfig) -> Result<Option<String>, Error> {
+    let visitors: Vec<Visitor> = config
+        .proxies
+        .iter()
+        .filter(|proxy| proxy.secret_key.is_some())
+        .map(|proxy| Visitor {
+            name: format!("{}-visitor", proxy.name),
+            type_: proxy.type_.clone(),
+            server_name: proxy.name.clone(),
+            secret_key: Some("<secret key>".to_string()),
+            bind_addr: Some("127.0.0.1".to_string()),
+            bind_port: proxy.local_port,
+        })
+        .collect();
+
+    if visitors.is_empty() {
+        return Ok(None);
+    }
+
+    let contents = toml::to_string(&VisitorConfig { visitors })
+        .map_err(|err| anyhow!("failed to serialize visitors: {err}"))?;
+
+    Ok(Some(contents))
+}
+
+fn is_load_balancer(svc: &Service) -> bool {
+    svc.spec.as_ref().is_some_and(|spec| {
+        spec.type_.as_deref() == Some("LoadBalancer")
+            && spec.load_balancer_class.as_deref() == Some("frp")
+    })
+}
+
+/// ExternalName Services can't carry a load balancer class, they opt in with
+/// the `expose` annotation instead.
+fn external_name(svc: &Service) -> Option<&str> {
+    svc.spec
+        .as_ref()
+        .filter(|spec| spec.type_.as_deref() == Some("ExternalName"))
+        .and_then(|spec| spec.external_name.as_deref())
+        .filter(|_| annotations::expose(svc.annotations()))
+}
+
+/// Services reserved for another load balancer, or that aren't one at all,
+/// can still be tunneled when opted in with the `expose` or `load-balancer`
+/// annotation. Their load balancer status is left alone.
+fn is_exposed(svc: &Service) -> bool {
+    svc.spec.as_ref().is_some_and(|spec| {
+        matches!(
+            spec.type_.as_deref(),
+            None | Some("ClusterIP" | "NodePort" | "LoadBalancer")
+        )
+    }) && annotations::expose(svc.annotations())
+}
+
+/// A LoadBalancer Service of another class has its status written by that
+/// load balancer, frp must leave it alone.
+fn is_foreign_load_balancer(svc: &Service) -> bool {
+    svc.spec.as_ref().is_some_and(|spec| {
+        spec.type_.as_deref() == Some("LoadBalancer")
+            && spec.load_balancer_class.as_deref() != Some("frp")
+    })
+}
+
+pub fn is_frp_service(svc: &Service) -> bool {
+    is_load_balancer(svc) || is_exposed(svc) || external_name(svc).is_some()
+}
+
+/// Compares the ip, hostname and ports the operator publishes. The API server
+/// fills in others, e.g. `ipMode` on kubernetes 1.30 and later, which would
+/// otherwise make every reconcile patch the status again.
+fn same_load_balancer(a: Option<&LoadBalancerStatus>, b: Option<&LoadBalancerStatus>) -> bool {
+    let owned = |status: Option<&LoadBalancerStatus>| {
+        status
+            .and_then(|status| status.ingress.as_ref())
+            .into_iter()
+            .flatten()
+            .map(|ingress| {
+                (
+                    ingress.ip.clone(),
+                    ingress.hostname.clone(),
+                    ingress.ports.clone(),
+                )
+            })
+            .collect::<Vec<_>>()
+    };
+
+    owned(a) == owned(b)
+}
+
+async fn clear_load_balancer_status(
+    svc: &Service,
+    service_api: &Api<Service>,
+) -> Result<(), Error> {
+    let published = svc
+        .status
+        .as_ref()
+        .and_then(|status| status.load_balancer.as_ref())
+        .is_some_and(|load_balancer| load_balancer.ingress.is_some());
+    if !published {
+        return Ok(());
+    }
+
+    service_api
+        .patch_status(
+            &svc.name_any(),
+            &PatchParams::apply(OPERATOR_MANAGER),
+            &Patch::Merge(json!({
+                "status": {
+                    "loadBalancer": {
+                        "ingress": null,
+                    },
+                },
+            })),
+        )
+        .await?;
+
+    Ok(())
+}
+
+/// Marks the Service's load balancer as not ready, patching the status only
+/// when the condition changed.
+async fn set_not_ready(
+    svc: &Service,
+    service_api: &Api<Service>,
+    reason: &str,
+    message: String,
+) -> Result<(), Error> {
+    let mut conditions = svc
+        .status
+        .as_ref()
+        .and_then(|status| status.conditions.clone())
+        .unwrap_or_default();
+    let current = conditions.clone();
+    set_condition(
+        &mut conditions,
+        Condition {
+            type_: "LoadBalancerReady".to_string(),
+            status: "False".to_string(),
+            reason: reason.to_string(),
+            message,
+            observed_generation: svc.metadata.generation,
+            last_transition_time: Time(Utc::now()),
+        },
+    );
+
+    if conditions != current {
+        service_api
+            .patch_status(
+                &svc.name_any(),
+                &PatchParams::apply(OPERATOR_MANAGER),
+                &Patch::Merge(json!({
+                    "status": {
+                        "conditions": conditions,
+                    },
+                })),
+            )
+            .await?;
+    }
+
+    Ok(())
+}
+
+/// Tears down a Service that was edited away from the frp load balancer class.
+async fn release(svc: &Service, service_api: &Api<Service>, ctx: &Context) -> Result<(), Error> {
+    let name = svc.name_any();
+
+    info!("service {name} is no longer an frp load balancer, releasing");
+
+    if frpc::has_config_proxy_file(&name).await {
+        frpc::remove_config_proxy_file(&name).await?;
+        frpc::reload(&ctx.options).await?;
+    }
+
+    if !is_foreign_load_balancer(svc) {
+        clear_load_balancer_status(svc, service_api).await?;
+    }
+
+    let finalizers: Vec<&String> = svc
+        .finalizers()
+        .iter()
+        .filter(|finalizer| *finalizer != SERVICE_FINALIZER)
+        .collect();
+
+    service_api
+        .patch(
+            &name,
+            &PatchParams::default(),
+            &Patch::Merge(json!({
+                "metadata": {
+                    "finalizers": finalizers,
+                    "resourceVersion": svc.resource_version(),
+                },
+            })),
+        )
+        .await?;
+
+    Ok(())
+}
+
+/// Returns when the tunnel of a Service with `expire-after` is taken down. The
+/// deadline is stamped in `expires-at` the first time it's seen, removing that
+/// annotation starts the countdown over.
+async fn expires_at(
+    svc: &Service,
+    service_api: &Api<Service>,
+) -> Result<Option<DateTime<Utc>>, Error> {
+    let annotations = svc.annotations();
+
+    let expires_at = match annotations::expire_after(annotations)? {
+        None if !annotations.contains_key(annotations::EXPIRES_AT) => return Ok(None),
+        None => None,
+        Some(expire_after) => match annotations::expires_at(annotations)? {
+            Some(expires_at) => return Ok(Some(expires_at)),
+            None => Some(
+                chrono::Duration::from_std(expire_after)
+                    .ok()
+                    .and_then(|expire_after| Utc::now().checked_add_signed(expire_after))
+                    .ok_or_else(|| anyhow!("expiry {expire_after:?} is out of range"))?,
+            ),
+        },
+    };
+
+    service_api
+        .patch(
+            &svc.name_any(),
+            &PatchParams::apply(OPERATOR_MANAGER),
+            &Patch::Merge(json!({
+                "metadata": {
+                    "annotations": {
+                        annotations::EXPIRES_AT: expires_at.map(|expires_at| expires_at.to_rfc3339()),
+                    },
+                },
+            })),
+        )
+        .await?;
+
+    Ok(expires_at)
+}
+
+async fn reconcile(obj: Arc<Service>, ctx: Arc<Context>) -> Result<Action, Error> {
     let obj_name = obj.name_any().to_owned();
     let obj_ns = obj.namespace().clone().unwrap_or("default".to_string());
 
@@ -81,45 +1084,282 @@ async fn reconcile(obj: Arc<Service>, ctx: Arc<Context>) -> Result<Action, Error
 
     let service_api: Api<Service> = Api::namespaced(client.clone(), &obj_ns);
 
+    let obj_annotations = obj.annotations().clone();
+
+    if !is_frp_service(&obj)
+        || !ctx.is_watched_namespace(&obj_ns).await
+        || !Options::is_selected(&ctx.options.service_selector, obj.labels())
+    {
+        if obj.finalizers().iter().any(|f| f == SERVICE_FINALIZER) {
+            release(&obj, &service_api, &ctx).await?;
+        }
+
+        return Ok(Action::requeue(Duration::from_secs(3600)));
+    }
+
     finalizer(&service_api, SERVICE_FINALIZER, obj, |event| async {
+        let mut ready = true;
+        let mut expires_in = None;
+
         match event {
             finalizer::Event::Apply(svc) => {
-                let config = proxy_from_service(&svc).await?;
-                frpc::write_config_proxy_to_file(config).await?;
+                if let Some(expires_at) = expires_at(&svc, &service_api).await? {
+                    if expires_at <= Utc::now() {
+                        if frpc::has_config_proxy_file(&obj_name).await {
+                            info!("service {obj_name} expired, unpublishing");
+
+                            frpc::remove_config_proxy_file(&obj_name).await?;
+                            frpc::reload(&ctx.options).await?;
+
+                            if is_load_balancer(&svc) {
+                                clear_load_balancer_status(&svc, &service_api).await?;
+                            }
+
+                            publish_event(
+                                &client,
+                                svc.as_ref(),
+                                EventType::Normal,
+                                "Expired",
+                                format!("tunnel expired at {}", expires_at.to_rfc3339()),
+                            )
+                            .await?;
+                        }
+
+                        set_not_ready(
+                            &svc,
+                            &service_api,
+                            "Expired",
+                            format!("tunnel expired at {}", expires_at.to_rfc3339()),
+                        )
+                        .await?;
+
+                        return Ok(Action::await_change());
+                    }
+
+                    expires_in = (expires_at - Utc::now()).to_std().ok();
+                }
+
+                let slices = endpoint_slices(&svc, &ctx).await?;
+
+                // don't route traffic into a black hole while nothing can serve it,
+                // ExternalName Services have no endpoints to check
+                if external_name(&svc).is_none() && !has_ready_endpoints(&slices) {
+                    info!("service {obj_name} has no ready endpoints, unpublishing");
+
+                    if frpc::has_config_proxy_file(&obj_name).await {
+                        frpc::remove_config_proxy_file(&obj_name).await?;
+                        frpc::reload(&ctx.options).await?;
+                    }
+
+                    if is_load_balancer(&svc) {
+                        clear_load_balancer_status(&svc, &service_api).await?;
+                    }
 
-                frpc::reload().await?;
+                    set_not_ready(
+                        &svc,
+                        &service_api,
+                        "NoReadyEndpoints",
+                        "service has no ready endpoints".to_string(),
+                    )
+                    .await?;
+
+                    return Ok(Action::requeue(Duration::from_secs(30)));
+                }
+
+                let mut skipped = vec![];
+                let config = proxy_from_service(&svc, &ctx, &slices, &mut skipped).await?;
+                let visitors = visitors_example(&config)?;
+                let allocated = allocated_ports(&svc, &config)?;
+                let allocated = (!allocated.is_empty())
+                    .then(|| serde_json::to_string(&allocated))
+                    .transpose()
+                    .map_err(|err| anyhow!("failed to serialize allocated ports: {err}"))?;
+                let ports = status_ports(&config, &ctx.options);
+                let (ip, hostname) =
+                    load_balancer_address(annotations::hostname(&obj_annotations)?).await;
+                let conflicts = remote_port_conflicts(&config).await?;
+                if !conflicts.is_empty() {
+                    let message = conflicts.join(", ");
+                    warn!("service {obj_ns}/{obj_name}: {message}");
+
+                    // reported once through the condition, the conflict is checked every minute
+                    let reported = svc
+                        .status
+                        .iter()
+                        .flat_map(|status| status.conditions.iter().flatten())
+                        .any(|c| {
+                            c.type_ == "LoadBalancerReady"
+                                && c.reason == "RemotePortConflict"
+                                && c.message == message
+                        });
+                    if !reported {
+                        publish_event(
+                            &client,
+                            svc.as_ref(),
+                            EventType::Warning,
+                            "RemotePortConflict",
+                            message.clone(),
+                        )
+                        .await?;
+                    }
+
+                    set_not_ready(&svc, &service_api, "RemotePortConflict", message).await?;
+
+                    return Ok(Action::requeue(Duration::from_secs(60)));
+                }
+
+                let proxy_names: Vec<String> = config
+                    .proxies
+                    .iter()
+                    .map(|proxy| proxy.name.clone())
+                    .collect();
+                // requeues waiting on proxies to start render the same config, those
+                // only poll the admin api instead of reloading frpc again
+                let unchanged = frpc::read_config_proxy_from_file(&obj_name)
+                    .await
+                    .is_ok_and(|current| current == config);
+                if !unchanged {
+                    // a rejected config keeps the previous proxy file, frpc isn't reloaded
+                    if let Err(err) =
+                        frpc::write_config_proxy_to_file(config, ctx.options.strict_config).await
+                    {
+                        if let Error::InvalidConfig(reason) = &err {
+                            publish_event(
+                                &client,
+                                svc.as_ref(),
+                                EventType::Warning,
+                                "InvalidConfig",
+                                reason.clone(),
+                            )
+                            .await?;
+                        }
+                        return Err(err);
+                    }
+
+                    frpc::reload(&ctx.options).await?;
+                }
+
+                let mut conditions = svc
+                    .status
+                    .as_ref()
+                    .and_then(|status| status.conditions.clone())
+                    .unwrap_or_default();
+                let condition =
+                    load_balancer_condition(&proxy_names, svc.metadata.generation).await;
+                ready = condition.status == "True";
+
+                let reported = conditions.iter().any(|c| {
+                    c.type_ == condition.type_
+                        && c.reason == condition.reason
+                        && c.message == condition.message
+                });
+                if condition.reason == "ProxiesRejected" && !reported {
+                    publish_event(
+                        &client,
+                        svc.as_ref(),
+                        EventType::Warning,
+                        "ProxiesRejected",
+                        condition.message.clone(),
+                    )
+                    .await?;
+                }
+
+                set_condition(&mut conditions, condition);
+
+                // reported once through the condition, like rejected proxies
+                if skipped.is_empty() {
+                    conditions.retain(|c| c.type_ != "PortsSupported");
+                } else {
+                    let message = format!("skipped SCTP ports {}", skipped.join(", "));
+                    let reported = conditions
+                        .iter()
+                        .any(|c| c.type_ == "PortsSupported" && c.message == message);
+                    if !reported {
+                        warn!("service {obj_ns}/{obj_name}: {message}");
+                        publish_event(
+                            &client,
+                            svc.as_ref(),
+                            EventType::Warning,
+                            "UnsupportedProtocol",
+                            message.clone(),
+                        )
+                        .await?;
+                    }
+
+                    set_condition(
+                        &mut conditions,
+                        Condition {
+                            type_: "PortsSupported".to_string(),
+                            status: "False".to_string(),
+                            reason: "UnsupportedProtocol".to_string(),
+                            message,
+                            observed_generation: svc.metadata.generation,
+                            last_transition_time: Time(Utc::now()),
+                        },
+                    );
+                }
 
-                let mut svc = service_api.get_status(&obj_name).await?;
-                svc.status = Some(ServiceStatus {
-                    load_balancer: Some(LoadBalancerStatus {
+                let status = ServiceStatus {
+                    load_balancer: is_load_balancer(&svc).then_some(LoadBalancerStatus {
                         ingress: Some(vec![LoadBalancerIngress {
-                            // hostname: todo!(),
-                            ip: frpc::read_config_from_file()
-                                .await
-                                .map(|config| config.server_addr)
-                                .ok(),
+                            hostname,
+                            ip,
+                            ports: Some(ports).filter(|ports| !ports.is_empty()),
                             ..LoadBalancerIngress::default()
                         }]),
                     }),
-                    ..ServiceStatus::default()
-                });
+                    conditions: Some(conditions),
+                };
 
-                service_api
-                    .patch_status(
-                        &obj_name,
-                        &PatchParams::apply(OPERATOR_MANAGER),
-                        &Patch::Merge(svc),
-                    )
-                    .await?;
+                // the load balancer is left untouched on Services that aren't frp load balancers
+                let current = svc.status.clone().unwrap_or_default();
+                if current.conditions != status.conditions
+                    || (status.load_balancer.is_some()
+                        && !same_load_balancer(
+                            current.load_balancer.as_ref(),
+                            status.load_balancer.as_ref(),
+                        ))
+                {
+                    service_api
+                        .patch_status(
+                            &obj_name,
+                            &PatchParams::apply(OPERATOR_MANAGER),
+                            &Patch::Merge(json!({ "status": status })),
+                        )
+                        .await?;
+                }
+
+                if obj_annotations.get(annotations::VISITORS) != visitors.as_ref()
+                    || obj_annotations.get(annotations::ALLOCATED_PORTS) != allocated.as_ref()
+                {
+                    service_api
+                        .patch(
+                            &obj_name,
+                            &PatchParams::apply(OPERATOR_MANAGER),
+                            &Patch::Merge(json!({
+                                "metadata": {
+                                    "annotations": {
+                                        annotations::VISITORS: visitors,
+                                        annotations::ALLOCATED_PORTS: allocated,
+                                    },
+                                },
+                            })),
+                        )
+                        .await?;
+                }
             }
             finalizer::Event::Cleanup(svc) => {
                 frpc::remove_config_proxy_file(&svc.name_any()).await?;
 
-                frpc::reload().await?;
+                frpc::reload(&ctx.options).await?;
             }
         }
 
-        return Ok(Action::requeue(Duration::from_secs(3600)));
+        // proxies frpc hasn't started yet are checked again shortly
+        let requeue = Duration::from_secs(if ready { 3600 } else { 15 });
+        Ok(Action::requeue(
+            expires_in.map_or(requeue, |expires_in: Duration| expires_in.min(requeue)),
+        ))
     })
     .await
     .map_err(|err| Error::FinalizerError(Box::new(err)))
@@ -130,18 +1370,40 @@ fn error_policy<K>(_obj: Arc<K>, err: &Error, _ctx: Arc<Context>) -> Action {
     Action::requeue(Duration::from_secs(15))
 }
 
-pub async fn run(ctx: Arc<Context>) -> anyhow::Result<()> {
+pub async fn run(
+    ctx: Arc<Context>,
+    services: Writer<Service>,
+    endpoint_slices: Writer<EndpointSlice>,
+) -> anyhow::Result<()> {
     let client = ctx.client.clone();
 
     let cfg = watcher::Config::default();
     let svc_api: Api<Service> = Api::all(client.clone());
 
-    let (reader, writer) = reflector::store();
-    let stream = reflector(writer, watcher(svc_api, cfg))
+    let reader = ctx.services.clone();
+    let stream = reflector(services, watcher(svc_api, cfg.clone()))
         .default_backoff()
-        .touched_objects();
+        .touched_objects()
+        .predicate_filter(reconcile_trigger);
 
-    Controller::for_stream(stream, reader)
+    // endpoint readiness decides whether and where a Service is published
+    let slice_api: Api<EndpointSlice> = Api::all(client.clone());
+    let slice_stream = reflector(
+        endpoint_slices,
+        watcher(slice_api, cfg.labels(SERVICE_NAME_LABEL)),
+    )
+    .default_backoff()
+    .touched_objects();
+
+    // bursts of edits to one object are coalesced into a single reload
+    let controller = Controller::for_stream(stream, reader)
+        .with_config(controller::Config::default().debounce(ctx.options.reconcile_debounce))
+        .watches_stream(slice_stream, |slice| {
+            let name = slice.labels().get(SERVICE_NAME_LABEL)?;
+            Some(ObjectRef::new(name).within(&slice.namespace()?))
+        });
+
+    watch_namespaces(controller, &ctx, ctx.services.clone())
         .shutdown_on_signal()
         .run(reconcile, error_policy, ctx.clone())
         .for_each(|res| async move {
@@ -154,3 +1416,353 @@ pub async fn run(ctx: Arc<Context>) -> anyhow::Result<()> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use k8s_openapi::{api::core::v1::ServiceSpec, apimachinery::pkg::apis::meta::v1::ObjectMeta};
+
+    use super::*;
+
+    fn service_port(port: i32, protocol: &str, app_protocol: Option<&str>) -> ServicePort {
+        ServicePort {
+            port,
+            protocol: Some(protocol.to_string()),
+            app_protocol: app_protocol.map(str::to_string),
+            ..ServicePort::default()
+        }
+    }
+
+    fn service(annotations: &[(&str, &str)], ports: Vec<ServicePort>) -> Service {
+        Service {
+            metadata: ObjectMeta {
+                name: Some("web".to_string()),
+                namespace: Some("shop".to_string()),
+                annotations: Some(
+                    annotations
+                        .iter()
+                        .map(|(key, value)| (key.to_string(), value.to_string()))
+                        .collect(),
+                ),
+                ..ObjectMeta::default()
+            },
+            spec: Some(ServiceSpec {
+                ports: Some(ports),
+                ..ServiceSpec::default()
+            }),
+            ..Service::default()
+        }
+    }
+
+    fn proxy(name: &str, type_: &str, remote_port: u16) -> Proxy {
+        Proxy {
+            name: name.to_string(),
+            type_: type_.to_string(),
+            remote_port: Some(remote_port),
+            ..Proxy::default()
+        }
+    }
+
+    #[test]
+    fn proxy_type_maps_dns_and_game_server_ports() {
+        // dns answers on both protocols of the same port
+        assert_eq!(proxy_type(&service_port(53, "UDP", None)).unwrap(), "udp");
+        assert_eq!(proxy_type(&service_port(53, "TCP", None)).unwrap(), "tcp");
+        // game servers usually speak udp, with a tcp rcon port next to it
+        assert_eq!(
+            proxy_type(&service_port(27015, "UDP", None)).unwrap(),
+            "udp"
+        );
+        assert_eq!(
+            proxy_type(&service_port(27015, "TCP", None)).unwrap(),
+            "tcp"
+        );
+        // an application protocol doesn't turn a udp port into a vhost
+        assert_eq!(
+            proxy_type(&service_port(443, "UDP", Some("https"))).unwrap(),
+            "udp"
+        );
+        assert!(proxy_type(&service_port(3868, "SCTP", None)).is_err());
+    }
+
+    #[test]
+    fn proxy_type_routes_http_app_protocols_as_vhosts() {
+        assert_eq!(
+            proxy_type(&service_port(80, "TCP", Some("http"))).unwrap(),
+            "http"
+        );
+        assert_eq!(
+            proxy_type(&service_port(80, "TCP", Some("kubernetes.io/ws"))).unwrap(),
+            "http"
+        );
+        assert_eq!(
+            proxy_type(&service_port(443, "TCP", Some("kubernetes.io/wss"))).unwrap(),
+            "https"
+        );
+        assert_eq!(
+            proxy_type(&ServicePort {
+                port: 8080,
+                ..ServicePort::default()
+            })
+            .unwrap(),
+            "tcp"
+        );
+    }
+
+    #[test]
+    fn validate_subdomains_rejects_shared_subdomains() {
+        let vhost = |name: &str, type_: &str, group: Option<&str>| Proxy {
+            name: name.to_string(),
+            type_: type_.to_string(),
+            subdomain: Some("web".to_string()),
+            load_balancer: group.map(|group| LoadBalancer {
+                group: group.to_string(),
+                group_key: "key".to_string(),
+            }),
+            ..Proxy::default()
+        };
+        let config = |proxies| ProxyConfig {
+            name: "web".to_string(),
+            proxies,
+        };
+
+        assert!(validate_subdomains(&config(vec![
+            vhost("web-http", "http", None),
+            vhost("web-https", "https", None),
+        ]))
+        .is_ok());
+        assert!(validate_subdomains(&config(vec![
+            vhost("web-0", "http", Some("web")),
+            vhost("web-1", "http", Some("web")),
+        ]))
+        .is_ok());
+        assert!(validate_subdomains(&config(vec![
+            vhost("web-http", "http", None),
+            vhost("web-alt", "http", None),
+        ]))
+        .is_err());
+        assert!(validate_subdomains(&config(vec![
+            vhost("web-http", "http", Some("a")),
+            vhost("web-alt", "http", Some("b")),
+        ]))
+        .is_err());
+    }
+
+    #[test]
+    fn proxy_name_expands_the_template() {
+        let http = ServicePort {
+            name: Some("http".to_string()),
+            ..service_port(80, "TCP", None)
+        };
+        let metrics = service_port(9090, "TCP", None);
+
+        let svc = service(&[], vec![]);
+        assert_eq!(proxy_name(&svc, &http).unwrap(), "svc-web-http");
+        assert_eq!(proxy_name(&svc, &metrics).unwrap(), "svc-web-9090");
+
+        let svc = service(
+            &[(annotations::PROXY_NAME_TEMPLATE, "{{ns}}.{{name}}.{{port}}")],
+            vec![],
+        );
+        assert_eq!(proxy_name(&svc, &http).unwrap(), "shop.web.http");
+        assert_eq!(proxy_name(&svc, &metrics).unwrap(), "shop.web.9090");
+    }
+
+    #[test]
+    fn proxy_name_template_must_tell_ports_apart() {
+        let ports = vec![
+            service_port(80, "TCP", None),
+            service_port(9090, "TCP", None),
+        ];
+
+        assert!(validate_service(&service(
+            &[(annotations::PROXY_NAME_TEMPLATE, "{{ns}}-{{name}}-{{port}}")],
+            ports.clone()
+        ))
+        .is_ok());
+        assert!(validate_service(&service(
+            &[(annotations::PROXY_NAME_TEMPLATE, "{{ns}}-{{name}}")],
+            ports
+        ))
+        .is_err());
+        // one port can't collide with itself
+        assert!(validate_service(&service(
+            &[(annotations::PROXY_NAME_TEMPLATE, "{{ns}}-{{name}}")],
+            vec![service_port(80, "TCP", None)]
+        ))
+        .is_ok());
+    }
+
+    #[test]
+    fn allocated_ports_reports_only_differing_remote_ports() {
+        let svc = service(
+            &[],
+            vec![
+                service_port(25565, "TCP", None),
+                service_port(27015, "UDP", None),
+                service_port(8080, "TCP", None),
+                ServicePort {
+                    name: Some("http".to_string()),
+                    ..service_port(80, "TCP", Some("http"))
+                },
+            ],
+        );
+        let config = ProxyConfig {
+            name: "web".to_string(),
+            proxies: vec![
+                proxy("svc-web-25565", "tcp", 30000),
+                // endpoints mode members carry an index
+                proxy("svc-web-27015-0", "udp", 27015),
+                proxy("svc-web-8080-0", "tcp", 30080),
+                proxy("svc-web-8080-1", "tcp", 30080),
+                Proxy {
+                    remote_port: None,
+                    ..proxy("svc-web-http", "http", 0)
+                },
+            ],
+        };
+
+        assert_eq!(
+            allocated_ports(&svc, &config).unwrap(),
+            BTreeMap::from([
+                ("25565/TCP".to_string(), 30000),
+                ("8080/TCP".to_string(), 30080),
+            ])
+        );
+    }
+
+    #[test]
+    fn target_mode_needs_a_headless_service() {
+        let service = |cluster_ip: &str, mode: Option<&str>| Service {
+            metadata: ObjectMeta {
+                name: Some("game".to_string()),
+                annotations: mode.map(|mode| {
+                    BTreeMap::from([(annotations::LOCAL_PORT_MODE.to_string(), mode.to_string())])
+                }),
+                ..ObjectMeta::default()
+            },
+            spec: Some(ServiceSpec {
+                cluster_ip: Some(cluster_ip.to_string()),
+                ..ServiceSpec::default()
+            }),
+            ..Service::default()
+        };
+
+        assert_eq!(
+            local_port_mode(&service("None", None)).unwrap(),
+            LocalPortMode::Target
+        );
+        assert_eq!(
+            local_port_mode(&service("None", Some("target"))).unwrap(),
+            LocalPortMode::Target
+        );
+        assert_eq!(
+            local_port_mode(&service("10.96.0.10", None)).unwrap(),
+            LocalPortMode::Service
+        );
+        assert!(local_port_mode(&service("10.96.0.10", Some("target"))).is_err());
+    }
+
+    #[test]
+    fn endpoints_mode_rejects_udp_ports() {
+        assert!(check_endpoints_port(&service_port(53, "TCP", None)).is_ok());
+        assert!(check_endpoints_port(&service_port(53, "UDP", None)).is_err());
+    }
+
+    #[test]
+    fn endpoints_mode_rejects_secret_proxies() {
+        let ports = vec![service_port(22, "TCP", None)];
+
+        assert!(validate_service(&service(
+            &[(annotations::LOCAL_PORT_MODE, "endpoints")],
+            ports.clone()
+        ))
+        .is_ok());
+        for annotation in [annotations::STCP_SECRET_REF, annotations::SUDP_SECRET_REF] {
+            assert!(validate_service(&service(
+                &[
+                    (annotations::LOCAL_PORT_MODE, "endpoints"),
+                    (annotation, "ssh-visitor/key"),
+                ],
+                ports.clone()
+            ))
+            .is_err());
+            assert!(
+                validate_service(&service(&[(annotation, "ssh-visitor/key")], ports.clone()))
+                    .is_ok()
+            );
+        }
+    }
+
+    #[test]
+    fn same_load_balancer_ignores_defaulted_fields() {
+        let status = |ip_mode: Option<&str>, ip: &str| LoadBalancerStatus {
+            ingress: Some(vec![LoadBalancerIngress {
+                ip: Some(ip.to_string()),
+                ip_mode: ip_mode.map(str::to_string),
+                ..LoadBalancerIngress::default()
+            }]),
+        };
+
+        assert!(same_load_balancer(
+            Some(&status(Some("VIP"), "203.0.113.10")),
+            Some(&status(None, "203.0.113.10"))
+        ));
+        assert!(!same_load_balancer(
+            Some(&status(Some("VIP"), "203.0.113.10")),
+            Some(&status(None, "203.0.113.11"))
+        ));
+        assert!(!same_load_balancer(
+            None,
+            Some(&status(None, "203.0.113.10"))
+        ));
+    }
+
+    #[test]
+    fn validate_remote_ports_allows_members_of_one_group() {
+        let member = |name: &str, group: &str| Proxy {
+            load_balancer: Some(LoadBalancer {
+                group: group.to_string(),
+                group_key: "key".to_string(),
+            }),
+            ..proxy(name, "tcp", 25565)
+        };
+
+        let config = |proxies| ProxyConfig {
+            name: "game".to_string(),
+            proxies,
+        };
+        assert!(validate_remote_ports(&config(vec![
+            member("game-0", "game"),
+            member("game-1", "game")
+        ]))
+        .is_ok());
+        assert!(validate_remote_ports(&config(vec![
+            member("game", "game-game"),
+            member("rcon", "game-rcon")
+        ]))
+        .is_err());
+        assert!(validate_remote_ports(&config(vec![
+            member("game", "game"),
+            proxy("rcon", "tcp", 25565)
+        ]))
+        .is_err());
+    }
+
+    #[test]
+    fn validate_remote_ports_allows_one_port_per_protocol() {
+        let config = ProxyConfig {
+            name: "dns".to_string(),
+            proxies: vec![proxy("dns-udp", "udp", 53), proxy("dns-tcp", "tcp", 53)],
+        };
+        assert!(validate_remote_ports(&config).is_ok());
+    }
+
+    #[test]
+    fn validate_remote_ports_rejects_collisions() {
+        let config = ProxyConfig {
+            name: "game".to_string(),
+            proxies: vec![proxy("game", "udp", 27015), proxy("query", "udp", 27015)],
+        };
+        assert!(validate_remote_ports(&config).is_err());
+    }
+}
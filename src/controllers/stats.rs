@@ -0,0 +1,317 @@
+use std::{collections::HashMap, fmt::Debug, sync::Arc};
+
+use anyhow::anyhow;
+use k8s_openapi::{
+    api::{core::v1::Service, networking::v1::Ingress},
+    NamespaceResourceScope,
+};
+use kube::{
+    api::{Patch, PatchParams},
+    runtime::reflector::Store,
+    Api, Resource, ResourceExt,
+};
+use log::{info, warn};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::json;
+
+use crate::{
+    annotations,
+    context::Context,
+    controllers::{ingress::is_frp_ingress, service::is_frp_service},
+    error::Error,
+    frpc,
+    frps::{Dashboard, ProxyStats},
+    metrics::METRICS,
+    OPERATOR_MANAGER,
+};
+
+#[derive(Default, Debug, Clone, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct TrafficStatus {
+    pub traffic_in: u64,
+    pub traffic_out: u64,
+    pub cur_conns: u64,
+    pub proxies: Vec<ProxyTraffic>,
+}
+
+#[derive(Default, Debug, Clone, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ProxyTraffic {
+    pub name: String,
+    #[serde(default)]
+    pub status: String,
+    #[serde(default)]
+    pub today_traffic_in: u64,
+    #[serde(default)]
+    pub today_traffic_out: u64,
+    /// Traffic since the proxy was first seen, accumulated from the daily counters.
+    #[serde(default)]
+    pub traffic_in: u64,
+    #[serde(default)]
+    pub traffic_out: u64,
+    #[serde(default)]
+    pub cur_conns: u64,
+}
+
+/// Adds the growth of a daily frps counter to its running total. A counter
+/// lower than last time was reset at midnight or by an frps restart, the
+/// traffic between the last poll and the reset isn't counted.
+fn accumulate(total: u64, previous_today: u64, today: u64) -> u64 {
+    if today >= previous_today {
+        total + (today - previous_today)
+    } else {
+        total + today
+    }
+}
+
+fn proxy_traffic(stats: &ProxyStats, previous: Option<&ProxyTraffic>) -> ProxyTraffic {
+    let previous = previous.cloned().unwrap_or_default();
+
+    ProxyTraffic {
+        name: stats.name.clone(),
+        status: stats.status.clone(),
+        today_traffic_in: stats.today_traffic_in,
+        today_traffic_out: stats.today_traffic_out,
+        traffic_in: accumulate(
+            previous.traffic_in,
+            previous.today_traffic_in,
+            stats.today_traffic_in,
+        ),
+        traffic_out: accumulate(
+            previous.traffic_out,
+            previous.today_traffic_out,
+            stats.today_traffic_out,
+        ),
+        cur_conns: stats.cur_conns,
+    }
+}
+
+/// Builds the traffic status of a resource, carrying the cumulative counters
+/// over from the status it published last.
+async fn traffic_status(
+    dashboard: &Dashboard,
+    cache: &mut HashMap<String, Vec<ProxyStats>>,
+    name: &str,
+    previous: &TrafficStatus,
+) -> Result<TrafficStatus, Error> {
+    let config = frpc::read_config_proxy_from_file(name).await?;
+
+    let mut status = TrafficStatus::default();
+    for proxy in config.proxies {
+        if !cache.contains_key(&proxy.type_) {
+            let stats = dashboard.proxies(&proxy.type_).await?;
+            cache.insert(proxy.type_.clone(), stats);
+        }
+
+        let Some(stats) = cache[&proxy.type_].iter().find(|s| s.name == proxy.name) else {
+            continue;
+        };
+
+        let traffic = proxy_traffic(
+            stats,
+            previous.proxies.iter().find(|p| p.name == proxy.name),
+        );
+
+        status.traffic_in += traffic.traffic_in;
+        status.traffic_out += traffic.traffic_out;
+        status.cur_conns += traffic.cur_conns;
+        status.proxies.push(traffic);
+    }
+
+    Ok(status)
+}
+
+/// Exports the traffic of a resource's proxies. The counters grow by the
+/// traffic since the status published last.
+fn record_metrics(
+    kind: &str,
+    ns: &str,
+    name: &str,
+    previous: &TrafficStatus,
+    status: &TrafficStatus,
+) {
+    for proxy in status.proxies.iter() {
+        let labels = [kind, ns, name, proxy.name.as_str()];
+        let previous = previous
+            .proxies
+            .iter()
+            .find(|p| p.name == proxy.name)
+            .cloned()
+            .unwrap_or_default();
+
+        METRICS
+            .proxy_connections
+            .with_label_values(&labels)
+            .set(proxy.cur_conns as i64);
+        METRICS
+            .proxy_traffic_in
+            .with_label_values(&labels)
+            .inc_by(proxy.traffic_in.saturating_sub(previous.traffic_in));
+        METRICS
+            .proxy_traffic_out
+            .with_label_values(&labels)
+            .inc_by(proxy.traffic_out.saturating_sub(previous.traffic_out));
+    }
+}
+
+/// Publishes the traffic of every managed resource of a kind in its status
+/// annotation, reading the resources from the controller's store.
+async fn update_resources<K>(
+    ctx: &Context,
+    store: &Store<K>,
+    dashboard: &Dashboard,
+    cache: &mut HashMap<String, Vec<ProxyStats>>,
+    is_managed: fn(&K) -> bool,
+) -> Result<(), Error>
+where
+    K: Resource<DynamicType = (), Scope = NamespaceResourceScope>
+        + Clone
+        + DeserializeOwned
+        + Debug,
+{
+    for obj in store.state() {
+        let name = obj.name_any();
+        let ns = obj.namespace().unwrap_or("default".to_string());
+
+        if !is_managed(&obj)
+            || obj.meta().deletion_timestamp.is_some()
+            || !ctx.is_watched_namespace(&ns).await
+            || !frpc::has_config_proxy_file(&name).await
+        {
+            continue;
+        }
+
+        let previous: TrafficStatus = obj
+            .annotations()
+            .get(annotations::STATUS)
+            .and_then(|contents| serde_json::from_str(contents).ok())
+            .unwrap_or_default();
+
+        let status = match traffic_status(dashboard, cache, &name, &previous).await {
+            Ok(status) => status,
+            Err(err) => {
+                warn!(
+                    "failed to collect traffic for {} {ns}/{name}: {err}",
+                    K::kind(&())
+                );
+                continue;
+            }
+        };
+        record_metrics(K::kind(&()).as_ref(), &ns, &name, &previous, &status);
+
+        let contents = serde_json::to_string(&status)
+            .map_err(|err| anyhow!("failed to serialize traffic status: {err}"))?;
+
+        if obj.annotations().get(annotations::STATUS) == Some(&contents) {
+            continue;
+        }
+
+        let api: Api<K> = Api::namespaced(ctx.client.clone(), &ns);
+        api.patch(
+            &name,
+            &PatchParams::apply(OPERATOR_MANAGER),
+            &Patch::Merge(json!({
+                "metadata": {
+                    "annotations": {
+                        annotations::STATUS: contents,
+                    },
+                },
+            })),
+        )
+        .await?;
+    }
+
+    Ok(())
+}
+
+pub async fn run(ctx: Arc<Context>) -> anyhow::Result<()> {
+    let Some(dashboard) = ctx.options.dashboard.clone() else {
+        return Ok(());
+    };
+
+    info!("polling traffic statistics from {}", dashboard.url);
+
+    let mut interval = tokio::time::interval(ctx.options.stats_interval);
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = tokio::signal::ctrl_c() => return Ok(()),
+        }
+
+        // proxies of both kinds are looked up in the same dashboard listings
+        let mut cache = HashMap::new();
+        // connections of proxies that went away aren't reported any more
+        METRICS.proxy_connections.reset();
+
+        if let Err(err) = update_resources::<Ingress>(
+            &ctx,
+            &ctx.ingresses,
+            &dashboard,
+            &mut cache,
+            is_frp_ingress,
+        )
+        .await
+        {
+            warn!("update ingress traffic statistics failed: {err}");
+        }
+
+        if let Err(err) =
+            update_resources::<Service>(&ctx, &ctx.services, &dashboard, &mut cache, is_frp_service)
+                .await
+        {
+            warn!("update service traffic statistics failed: {err}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accumulate_adds_growth_and_survives_resets() {
+        assert_eq!(accumulate(0, 0, 100), 100);
+        assert_eq!(accumulate(100, 100, 150), 150);
+        // the daily counter started over
+        assert_eq!(accumulate(150, 150, 20), 170);
+        assert_eq!(accumulate(170, 20, 20), 170);
+    }
+
+    #[test]
+    fn record_metrics_counts_traffic_since_the_last_status() {
+        let status = |traffic_in: u64, cur_conns: u64| TrafficStatus {
+            proxies: vec![ProxyTraffic {
+                name: "svc-metrics-web".to_string(),
+                traffic_in,
+                cur_conns,
+                ..ProxyTraffic::default()
+            }],
+            ..TrafficStatus::default()
+        };
+
+        record_metrics(
+            "Service",
+            "default",
+            "metrics",
+            &status(100, 0),
+            &status(150, 2),
+        );
+        record_metrics(
+            "Service",
+            "default",
+            "metrics",
+            &status(150, 2),
+            &status(175, 3),
+        );
+
+        let labels = ["Service", "default", "metrics", "svc-metrics-web"];
+        assert_eq!(
+            METRICS.proxy_traffic_in.with_label_values(&labels).get(),
+            75
+        );
+        assert_eq!(
+            METRICS.proxy_connections.with_label_values(&labels).get(),
+            3
+        );
+    }
+}
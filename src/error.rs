@@ -1,6 +1,7 @@
 use thiserror::Error;
 
 #[derive(Debug, Error)]
+#[allow(clippy::enum_variant_names)]
 pub enum Error {
     #[error("io Error: {0}")]
     IoError(#[from] std::io::Error),
@@ -8,6 +9,10 @@ pub enum Error {
     KubeError(#[from] kube::Error),
     #[error("Finalizer Error: {0}")]
     FinalizerError(#[source] Box<kube::runtime::finalizer::Error<Error>>),
+    #[error("invalid annotation {0}: {1}")]
+    InvalidAnnotation(String, String),
+    #[error("frpc rejected config: {0}")]
+    InvalidConfig(String),
     #[error(transparent)]
     Other(#[from] anyhow::Error),
 }
@@ -0,0 +1,267 @@
+use std::{collections::HashMap, net::IpAddr};
+
+use anyhow::anyhow;
+use reqwest::Method;
+use serde::{Deserialize, Serialize};
+use tokio::fs;
+
+use crate::error::Error;
+
+use super::{join_host_port, read_config_from_file, read_config_proxies_from_files, unbracket};
+
+/// Metadata marking the proxies the operator pushed to an unmanaged frpc.
+const MANAGED_METADATA: &str = "frp-operator.io/managed";
+
+#[derive(Default, Debug, Clone, Deserialize, Serialize, PartialEq)]
+pub struct ProxyStatus {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub type_: String,
+    pub status: String,
+    #[serde(default)]
+    pub err: String,
+    #[serde(default)]
+    pub local_addr: String,
+    #[serde(default)]
+    pub remote_addr: String,
+}
+
+impl ProxyStatus {
+    pub fn is_running(&self) -> bool {
+        self.status == "running"
+    }
+}
+
+fn is_loopback(addr: &str) -> bool {
+    addr == "localhost" || addr.parse::<IpAddr>().is_ok_and(|ip| ip.is_loopback())
+}
+
+/// Builds a request to the frpc admin api, using the webserver credentials
+/// and scheme from the root config.
+async fn admin_request(method: Method, path: &str) -> Result<reqwest::RequestBuilder, Error> {
+    let config = read_config_from_file().await?;
+    let webserver = config
+        .webserver
+        .ok_or_else(|| anyhow!("frpc webserver is not enabled"))?;
+
+    let addr = match webserver.addr.as_deref().map(unbracket) {
+        None | Some("0.0.0.0") | Some("") => "127.0.0.1",
+        Some("::") => "::1",
+        Some(addr) => addr,
+    };
+
+    let (scheme, client) = match webserver.tls {
+        None => ("http", reqwest::Client::new()),
+        Some(tls) => {
+            let mut builder = reqwest::Client::builder();
+            if is_loopback(addr) {
+                // the operator's own frpc, its certificate rarely names the loopback address
+                builder = builder.danger_accept_invalid_certs(true);
+            } else if let Some(ca_file) = tls.trusted_ca_file.as_ref() {
+                let ca = fs::read(ca_file)
+                    .await
+                    .map_err(|err| anyhow!("failed to read trusted ca {ca_file}: {err}"))?;
+                let ca = reqwest::Certificate::from_pem(&ca)
+                    .map_err(|err| anyhow!("failed to parse trusted ca {ca_file}: {err}"))?;
+                builder = builder.add_root_certificate(ca);
+            }
+
+            (
+                "https",
+                builder
+                    .build()
+                    .map_err(|err| anyhow!("failed to build frpc admin api client: {err}"))?,
+            )
+        }
+    };
+
+    let url = format!("{scheme}://{}{path}", join_host_port(addr, webserver.port));
+
+    let mut req = client.request(method, url);
+    if let Some(user) = webserver.user.as_ref() {
+        req = req.basic_auth(user, webserver.password.as_ref());
+    }
+
+    Ok(req)
+}
+
+/// Checks the frpc admin api answers its health endpoint.
+pub async fn healthz() -> Result<(), Error> {
+    admin_request(Method::GET, "/healthz")
+        .await?
+        .send()
+        .await
+        .and_then(|res| res.error_for_status())
+        .map_err(|err| anyhow!("frpc admin api is unhealthy: {err}"))?;
+
+    Ok(())
+}
+
+/// Returns the state frpc reports for every proxy, keyed by proxy name.
+pub async fn status() -> Result<HashMap<String, ProxyStatus>, Error> {
+    let res = admin_request(Method::GET, "/api/status")
+        .await?
+        .send()
+        .await
+        .and_then(|res| res.error_for_status())
+        .map_err(|err| anyhow!("failed to query frpc admin api: {err}"))?
+        .json::<HashMap<String, Vec<ProxyStatus>>>()
+        .await
+        .map_err(|err| anyhow!("failed to decode frpc admin api response: {err}"))?;
+
+    Ok(res
+        .into_values()
+        .flatten()
+        .map(|status| (status.name.clone(), status))
+        .collect())
+}
+
+/// Lists the proxies of the proxy files that frpc doesn't know, e.g. after an
+/// unmanaged frpc restarted with its own config.
+pub async fn missing_proxies() -> Result<Vec<String>, Error> {
+    let statuses = status().await?;
+
+    Ok(read_config_proxies_from_files()
+        .await?
+        .into_iter()
+        .flat_map(|config| config.proxies)
+        .map(|proxy| proxy.name)
+        .filter(|name| !statuses.contains_key(name))
+        .collect())
+}
+
+/// Marks the block of proxies the operator appends to an unmanaged frpc's
+/// config, everything outside it is left as the user wrote it.
+const MANAGED_BEGIN: &str = "# BEGIN proxies managed by frp-operator, edits are overwritten";
+const MANAGED_END: &str = "# END proxies managed by frp-operator";
+
+/// Replaces the managed block of a config with the given proxies, appending
+/// it when the config has none yet.
+fn replace_managed_block(contents: &str, managed: &str) -> String {
+    let unmanaged = match contents.find(MANAGED_BEGIN) {
+        Some(begin) => {
+            let end = contents[begin..]
+                .find(MANAGED_END)
+                .map_or(contents.len(), |end| begin + end + MANAGED_END.len());
+            format!("{}{}", &contents[..begin], &contents[end..])
+        }
+        None => contents.to_string(),
+    };
+
+    let unmanaged = unmanaged.trim_end();
+    if managed.is_empty() {
+        return format!("{unmanaged}\n");
+    }
+
+    format!("{unmanaged}\n\n{MANAGED_BEGIN}\n{managed}{MANAGED_END}\n")
+}
+
+/// Replaces the proxies the operator previously pushed to an unmanaged frpc
+/// with the current proxy files and reloads it. The config is edited as text,
+/// so the proxies, comments and templates of the user stay untouched. The
+/// proxies point at cluster DNS names such as `<svc>.<ns>.svc.cluster.local`,
+/// the external frpc has to resolve and reach those.
+pub async fn push(strict_config: bool) -> Result<(), Error> {
+    let contents = admin_request(Method::GET, "/api/config")
+        .await?
+        .send()
+        .await
+        .and_then(|res| res.error_for_status())
+        .map_err(|err| anyhow!("failed to get frpc config: {err}"))?
+        .text()
+        .await
+        .map_err(|err| anyhow!("failed to read frpc config: {err}"))?;
+
+    let mut proxies = vec![];
+    for mut proxy in read_config_proxies_from_files()
+        .await?
+        .into_iter()
+        .flat_map(|config| config.proxies)
+    {
+        proxy
+            .metadatas
+            .get_or_insert_with(Default::default)
+            .insert(MANAGED_METADATA.to_string(), "true".to_string());
+
+        proxies.push(
+            toml::Value::try_from(&proxy)
+                .map_err(|err| anyhow!("failed to serialize proxy {}: {err}", proxy.name))?,
+        );
+    }
+
+    let managed = if proxies.is_empty() {
+        String::new()
+    } else {
+        let mut table = toml::Table::new();
+        table.insert("proxies".to_string(), toml::Value::Array(proxies));
+        toml::to_string(&table).map_err(|err| anyhow!("failed to serialize proxies: {err}"))?
+    };
+
+    let updated = replace_managed_block(&contents, &managed);
+    if updated == contents {
+        return Ok(());
+    }
+
+    admin_request(Method::PUT, "/api/config")
+        .await?
+        .body(updated)
+        .send()
+        .await
+        .and_then(|res| res.error_for_status())
+        .map_err(|err| anyhow!("failed to put frpc config: {err}"))?;
+
+    reload(strict_config).await
+}
+
+/// Asks frpc to reload its config, returning the reason it gives when it
+/// rejects the new one.
+pub async fn reload(strict_config: bool) -> Result<(), Error> {
+    let res = admin_request(Method::GET, "/api/reload")
+        .await?
+        .query(&[("strictConfig", strict_config)])
+        .send()
+        .await
+        .map_err(|err| anyhow!("failed to reload frpc: {err}"))?;
+
+    let status = res.status();
+    if !status.is_success() {
+        let body = res.text().await.unwrap_or_default();
+        return Err(anyhow!("frpc rejected reload ({status}): {}", body.trim()).into());
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MANAGED: &str = "[[proxies]]\nname = \"svc-web-http\"\n";
+
+    #[test]
+    fn replace_managed_block_appends_to_the_user_config() {
+        let contents =
+            "# edge box\nserverAddr = \"{{ .Envs.FRPS }}\"\n\n[[proxies]]\nname = \"ssh\"\n";
+
+        let updated = replace_managed_block(contents, MANAGED);
+        assert_eq!(
+            updated,
+            format!("{}\n{MANAGED_BEGIN}\n{MANAGED}{MANAGED_END}\n", contents)
+        );
+        // pushing the same proxies again leaves the config as is
+        assert_eq!(replace_managed_block(&updated, MANAGED), updated);
+    }
+
+    #[test]
+    fn replace_managed_block_replaces_and_removes_the_block() {
+        let contents = "serverPort = 7000\n";
+        let updated = replace_managed_block(contents, MANAGED);
+
+        let other = "[[proxies]]\nname = \"svc-api-http\"\n";
+        assert_eq!(
+            replace_managed_block(&updated, other),
+            format!("serverPort = 7000\n\n{MANAGED_BEGIN}\n{other}{MANAGED_END}\n")
+        );
+        assert_eq!(replace_managed_block(&updated, ""), contents);
+    }
+}
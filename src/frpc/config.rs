@@ -1,5 +1,10 @@
+use std::collections::BTreeMap;
+
+use anyhow::anyhow;
 use serde::{Deserialize, Serialize};
 
+use crate::error::Error;
+
 #[derive(Default, Debug, Clone, Deserialize, Serialize, PartialEq)]
 #[serde(rename_all = "camelCase")]
 pub struct LoadBalancer {
@@ -17,10 +22,27 @@ pub struct Proxy {
     pub local_port: Option<u16>,
     pub remote_port: Option<u16>,
     pub custom_domains: Option<Vec<String>>,
+    pub subdomain: Option<String>,
     pub locations: Option<Vec<String>>,
     pub plugin: Option<ProxyPlugin>,
     pub load_balancer: Option<LoadBalancer>,
     pub transport: Option<ProxyTransport>,
+    pub metadatas: Option<BTreeMap<String, String>>,
+    pub secret_key: Option<String>,
+    pub health_check: Option<HealthCheck>,
+    /// Fields the operator doesn't model, merged in from `proxy-snippet`.
+    #[serde(flatten)]
+    pub extra: toml::Table,
+}
+
+#[derive(Default, Debug, Clone, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct HealthCheck {
+    #[serde(rename = "type")]
+    pub type_: String,
+    pub timeout_seconds: Option<u32>,
+    pub max_failed: Option<u32>,
+    pub interval_seconds: Option<u32>,
 }
 
 #[derive(Default, Debug, Clone, Deserialize, Serialize, PartialEq)]
@@ -28,6 +50,21 @@ pub struct Proxy {
 pub struct Auth {
     pub method: String,
     pub token: Option<String>,
+    pub oidc: Option<OidcAuth>,
+    #[serde(flatten)]
+    pub extra: toml::Table,
+}
+
+#[derive(Default, Debug, Clone, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct OidcAuth {
+    #[serde(rename = "clientID")]
+    pub client_id: String,
+    pub client_secret: Option<String>,
+    pub audience: Option<String>,
+    pub scope: Option<String>,
+    #[serde(rename = "tokenEndpointURL")]
+    pub token_endpoint_url: String,
 }
 
 #[derive(Default, Debug, Clone, Deserialize, Serialize, PartialEq)]
@@ -35,6 +72,11 @@ pub struct Auth {
 pub struct WebServer {
     pub addr: Option<String>,
     pub port: u16,
+    pub user: Option<String>,
+    pub password: Option<String>,
+    pub tls: Option<TlsConfig>,
+    #[serde(flatten)]
+    pub extra: toml::Table,
 }
 
 #[derive(Default, Debug, Clone, Deserialize, Serialize, PartialEq)]
@@ -49,6 +91,27 @@ pub struct ClientConfig {
     #[serde(skip_serializing_if = "Vec::is_empty", default = "Vec::new")]
     pub includes: Vec<String>,
     pub transport: Option<Transport>,
+    pub dns_server: Option<String>,
+    pub login_fail_exit: Option<bool>,
+    pub udp_packet_size: Option<u32>,
+    pub nat_hole_stun_server: Option<String>,
+    pub metadatas: Option<BTreeMap<String, String>>,
+    pub log: Option<Log>,
+    #[serde(skip_serializing_if = "Vec::is_empty", default = "Vec::new")]
+    pub start: Vec<String>,
+    /// Root settings the operator doesn't model, merged in from `--extra-config`.
+    #[serde(flatten)]
+    pub extra: toml::Table,
+}
+
+#[derive(Default, Debug, Clone, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Log {
+    pub to: Option<String>,
+    pub level: Option<String>,
+    pub max_days: Option<u32>,
+    #[serde(flatten)]
+    pub extra: toml::Table,
 }
 
 #[derive(Default, Debug, Clone, Deserialize, Serialize, PartialEq)]
@@ -77,10 +140,225 @@ pub struct ProxyPlugin {
 #[serde(rename_all = "camelCase")]
 pub struct ProxyTransport {
     pub proxy_protocol_version: Option<String>,
+    pub bandwidth_limit: Option<String>,
+    pub bandwidth_limit_mode: Option<String>,
+    #[serde(flatten)]
+    pub extra: toml::Table,
 }
 
 #[derive(Default, Debug, Clone, Deserialize, Serialize, PartialEq)]
 #[serde(rename_all = "camelCase")]
 pub struct Transport {
     pub protocol: Option<String>,
+    pub pool_count: Option<u32>,
+    pub heartbeat_interval: Option<i64>,
+    pub heartbeat_timeout: Option<i64>,
+    pub dial_server_timeout: Option<i64>,
+    pub dial_server_keepalive: Option<i64>,
+    pub tcp_mux: Option<bool>,
+    pub tcp_mux_keepalive_interval: Option<i64>,
+    #[serde(rename = "proxyURL")]
+    pub proxy_url: Option<String>,
+    #[serde(flatten)]
+    pub extra: toml::Table,
+    pub quic: Option<QuicOptions>,
+    pub tls: Option<TlsConfig>,
+}
+
+#[derive(Default, Debug, Clone, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct TlsConfig {
+    pub enable: Option<bool>,
+    pub cert_file: Option<String>,
+    pub key_file: Option<String>,
+    pub trusted_ca_file: Option<String>,
+    pub server_name: Option<String>,
+    #[serde(flatten)]
+    pub extra: toml::Table,
+}
+
+#[derive(Default, Debug, Clone, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct QuicOptions {
+    pub keepalive_period: Option<u64>,
+    pub max_idle_timeout: Option<u64>,
+    pub max_incoming_streams: Option<u64>,
+}
+
+#[derive(Default, Debug, Clone, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Visitor {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub type_: String,
+    pub server_name: String,
+    pub secret_key: Option<String>,
+    pub bind_addr: Option<String>,
+    pub bind_port: Option<u16>,
+}
+
+#[derive(Default, Debug, Clone, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct VisitorConfig {
+    #[serde(skip_serializing_if = "Vec::is_empty", default = "Vec::new")]
+    pub visitors: Vec<Visitor>,
+}
+
+/// Deep merges `overlay` into `base`, overlay values win.
+pub fn merge_table(base: &mut toml::Table, overlay: toml::Table) {
+    for (key, value) in overlay {
+        match (base.get_mut(&key), value) {
+            (Some(toml::Value::Table(base)), toml::Value::Table(overlay)) => {
+                merge_table(base, overlay)
+            }
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+/// Returns the first key of `overlay`, dotted, that `table` doesn't carry.
+pub fn missing_key(table: &toml::Table, overlay: &toml::Table) -> Option<String> {
+    overlay
+        .iter()
+        .find_map(|(key, value)| match (table.get(key), value) {
+            (None, _) => Some(key.to_owned()),
+            (Some(toml::Value::Table(table)), toml::Value::Table(overlay)) => {
+                missing_key(table, overlay).map(|nested| format!("{key}.{nested}"))
+            }
+            _ => None,
+        })
+}
+
+impl ClientConfig {
+    /// Merges raw frpc settings over the config, failing on keys a typed
+    /// section would drop.
+    pub fn merge(self, extra: toml::Table) -> Result<ClientConfig, Error> {
+        let mut table = toml::Table::try_from(&self)
+            .map_err(|err| anyhow!("failed to serialize config: {err}"))?;
+        merge_table(&mut table, extra.clone());
+
+        let merged: ClientConfig = table
+            .try_into()
+            .map_err(|err| anyhow!("invalid extra config: {err}"))?;
+
+        let table = toml::Table::try_from(&merged)
+            .map_err(|err| anyhow!("failed to serialize config: {err}"))?;
+        if let Some(key) = missing_key(&table, &extra) {
+            return Err(anyhow!("invalid extra config: {key} is not supported").into());
+        }
+
+        Ok(merged)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn table(contents: &str) -> toml::Table {
+        toml::from_str(contents).unwrap()
+    }
+
+    #[test]
+    fn merge_table_overrides_and_merges_nested_tables() {
+        let mut base = table(
+            r#"
+            serverPort = 7000
+            [transport]
+            protocol = "quic"
+            poolCount = 5
+            "#,
+        );
+        merge_table(
+            &mut base,
+            table(
+                r#"
+                serverPort = 7001
+                [transport]
+                protocol = "tcp"
+                [transport.tls]
+                enable = false
+                "#,
+            ),
+        );
+
+        assert_eq!(
+            base,
+            table(
+                r#"
+                serverPort = 7001
+                [transport]
+                protocol = "tcp"
+                poolCount = 5
+                [transport.tls]
+                enable = false
+                "#,
+            )
+        );
+    }
+
+    #[test]
+    fn missing_key_reports_the_dotted_path() {
+        let merged = table("[transport.quic]\nkeepalivePeriod = 10\n");
+
+        assert_eq!(
+            missing_key(&merged, &table("[transport.quic]\nkeepalivePeriod = 10\n")),
+            None
+        );
+        assert_eq!(
+            missing_key(&merged, &table("[transport.quic]\nmaxStreams = 10\n")),
+            Some("transport.quic.maxStreams".to_string())
+        );
+        assert_eq!(
+            missing_key(&merged, &table("user = \"edge\"\n")),
+            Some("user".to_string())
+        );
+    }
+
+    #[test]
+    fn merge_lets_extra_config_win() {
+        let config = ClientConfig {
+            server_addr: "203.0.113.10".to_string(),
+            server_port: 7000,
+            transport: Some(Transport {
+                protocol: Some("quic".to_string()),
+                ..Transport::default()
+            }),
+            ..ClientConfig::default()
+        };
+
+        let merged = config
+            .merge(table(
+                r#"
+                user = "edge"
+                [transport]
+                protocol = "tcp"
+                tcpMuxKeepaliveInterval = 30
+                "#,
+            ))
+            .unwrap();
+
+        assert_eq!(merged.server_addr, "203.0.113.10");
+        assert_eq!(merged.extra.get("user"), Some(&toml::Value::from("edge")));
+        let transport = merged.transport.unwrap();
+        assert_eq!(transport.protocol.as_deref(), Some("tcp"));
+        assert_eq!(transport.tcp_mux_keepalive_interval, Some(30));
+    }
+
+    #[test]
+    fn merge_rejects_keys_a_typed_section_drops() {
+        let err = ClientConfig::default()
+            .merge(table("[transport.quic]\nmaxStreams = 10\n"))
+            .unwrap_err();
+        assert!(
+            err.to_string().contains("transport.quic.maxStreams"),
+            "{err}"
+        );
+
+        assert!(ClientConfig::default()
+            .merge(table("serverPort = \"7000\"\n"))
+            .is_err());
+    }
 }
@@ -1,17 +1,35 @@
-use std::process::Stdio;
+use std::{io::ErrorKind, process::Stdio};
 
 use anyhow::anyhow;
 use log::info;
 use tokio::{fs, process::Command};
 
-use crate::error::Error;
+use crate::{context::Options, error::Error};
 
 use self::config::{ClientConfig, ProxyConfig};
 
+pub mod admin;
 pub mod config;
 
-const BASE_CONFIG_DIR: &'static str = "/etc/frp";
-const ROOT_CONFIG_PATH: &'static str = "/etc/frp/frpc.toml";
+const BASE_CONFIG_DIR: &str = "/etc/frp";
+const ROOT_CONFIG_PATH: &str = "/etc/frp/frpc.toml";
+
+/// Strips the brackets an IPv6 literal may be written with, frp expects bare addresses.
+pub fn unbracket(host: &str) -> &str {
+    host.strip_prefix('[')
+        .and_then(|host| host.strip_suffix(']'))
+        .unwrap_or(host)
+}
+
+/// Joins a host and port into an address, bracketing IPv6 literals.
+pub fn join_host_port(host: &str, port: u16) -> String {
+    let host = unbracket(host);
+    if host.contains(':') {
+        format!("[{host}]:{port}")
+    } else {
+        format!("{host}:{port}")
+    }
+}
 
 pub async fn read_config_from_file() -> Result<ClientConfig, Error> {
     let contents = fs::read_to_string(ROOT_CONFIG_PATH)
@@ -24,7 +42,34 @@ pub async fn read_config_from_file() -> Result<ClientConfig, Error> {
     Ok(config)
 }
 
-pub async fn write_config_to_file(config: ClientConfig) -> Result<(), Error> {
+/// Writes a config file only once `frpc verify` accepts it, so a rejected
+/// config leaves the previous good one in place.
+async fn write_verified(path: &str, contents: &str, strict_config: bool) -> Result<(), Error> {
+    // the staged name doesn't match the proxy-*.toml includes
+    let staged = format!("{path}.staged");
+    fs::write(&staged, contents)
+        .await
+        .map_err(|err| anyhow!("failed to write config {staged}: {err}"))?;
+
+    if let Err(err) = verify(&staged, strict_config).await {
+        let _ = fs::remove_file(&staged).await;
+        return Err(err);
+    }
+
+    fs::rename(&staged, path)
+        .await
+        .map_err(|err| anyhow!("failed to write config {path}: {err}"))?;
+
+    Ok(())
+}
+
+/// Writes the root config. An unmanaged frpc never loads it, it only tells the
+/// operator how to reach the external admin api, so it isn't verified.
+pub async fn write_config_to_file(
+    config: ClientConfig,
+    strict_config: bool,
+    unmanaged: bool,
+) -> Result<(), Error> {
     fs::create_dir_all(BASE_CONFIG_DIR)
         .await
         .map_err(|err| anyhow!("failed to create config directory {BASE_CONFIG_DIR}: {err}"))?;
@@ -32,9 +77,13 @@ pub async fn write_config_to_file(config: ClientConfig) -> Result<(), Error> {
     let contents =
         toml::to_string(&config).map_err(|err| anyhow!("failed to serialize config: {err}"))?;
 
-    fs::write(ROOT_CONFIG_PATH, &contents)
-        .await
-        .map_err(|err| anyhow!("failed to write config {ROOT_CONFIG_PATH}: {err}"))?;
+    if unmanaged {
+        fs::write(ROOT_CONFIG_PATH, &contents)
+            .await
+            .map_err(|err| anyhow!("failed to write config {ROOT_CONFIG_PATH}: {err}"))?;
+    } else {
+        write_verified(ROOT_CONFIG_PATH, &contents, strict_config).await?;
+    }
 
     info!("wrote root config to {ROOT_CONFIG_PATH}");
     info!("{contents}");
@@ -42,14 +91,15 @@ pub async fn write_config_to_file(config: ClientConfig) -> Result<(), Error> {
     Ok(())
 }
 
-pub async fn write_config_proxy_to_file(config: ProxyConfig) -> Result<(), Error> {
+pub async fn write_config_proxy_to_file(
+    config: ProxyConfig,
+    strict_config: bool,
+) -> Result<(), Error> {
     let contents =
         toml::to_string(&config).map_err(|err| anyhow!("failed to serialize config: {err}"))?;
 
     let path = format!("{BASE_CONFIG_DIR}/proxy-{}.toml", config.name);
-    fs::write(&path, &contents)
-        .await
-        .map_err(|err| anyhow!("failed to write config proxy {path}: {err}"))?;
+    write_verified(&path, &contents, strict_config).await?;
 
     info!("wrote config: {} to {path}", config.name);
     info!("{contents}");
@@ -57,21 +107,98 @@ pub async fn write_config_proxy_to_file(config: ProxyConfig) -> Result<(), Error
     Ok(())
 }
 
+pub async fn read_config_proxy_from_file(name: &str) -> Result<ProxyConfig, Error> {
+    let path = format!("{BASE_CONFIG_DIR}/proxy-{name}.toml");
+    let contents = fs::read_to_string(&path)
+        .await
+        .map_err(|err| anyhow!("failed to read config proxy {path}: {err}"))?;
+
+    let mut config: ProxyConfig =
+        toml::from_str(&contents).map_err(|err| anyhow!("failed to deserialize config: {err}"))?;
+    config.name = name.to_string();
+
+    Ok(config)
+}
+
+/// Lists the names of the proxy files in the config directory.
+pub async fn config_proxy_names() -> Result<Vec<String>, Error> {
+    let mut entries = match fs::read_dir(BASE_CONFIG_DIR).await {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(vec![]),
+        Err(err) => {
+            return Err(anyhow!("failed to read config directory {BASE_CONFIG_DIR}: {err}").into())
+        }
+    };
+
+    let mut names = vec![];
+    while let Some(entry) = entries.next_entry().await? {
+        let file_name = entry.file_name();
+        if let Some(name) = file_name
+            .to_str()
+            .and_then(|file_name| file_name.strip_prefix("proxy-"))
+            .and_then(|file_name| file_name.strip_suffix(".toml"))
+        {
+            names.push(name.to_string());
+        }
+    }
+
+    Ok(names)
+}
+
+/// Reads every proxy file in the config directory.
+pub async fn read_config_proxies_from_files() -> Result<Vec<ProxyConfig>, Error> {
+    let mut configs = vec![];
+    for name in config_proxy_names().await? {
+        configs.push(read_config_proxy_from_file(&name).await?);
+    }
+
+    Ok(configs)
+}
+
 pub async fn remove_config_proxy_file(name: &str) -> Result<(), Error> {
     let path = format!("{BASE_CONFIG_DIR}/proxy-{name}.toml");
-    fs::remove_file(&path)
+    match fs::remove_file(&path).await {
+        Err(err) if err.kind() != ErrorKind::NotFound => {
+            Err(anyhow!("failed to remove config proxy {path}: {err}").into())
+        }
+        _ => Ok(()),
+    }
+}
+
+pub async fn has_config_proxy_file(name: &str) -> bool {
+    let path = format!("{BASE_CONFIG_DIR}/proxy-{name}.toml");
+    fs::try_exists(&path).await.unwrap_or(false)
+}
+
+/// Checks a config file with `frpc verify`, returning what frpc reports when
+/// it's invalid.
+pub async fn verify(path: &str, strict_config: bool) -> Result<(), Error> {
+    let output = Command::new("/app/frpc")
+        .stdin(Stdio::null())
+        .args(["verify", "-c", path])
+        .arg(format!("--strict_config={strict_config}"))
+        .output()
         .await
-        .map_err(|err| anyhow!("failed to remove config proxy {path}: {err}"))?;
+        .map_err(|err| anyhow!("failed to spawn frpc: {err}"))?;
+
+    if !output.status.success() {
+        return Err(Error::InvalidConfig(format!(
+            "{}{}",
+            String::from_utf8_lossy(&output.stdout).trim(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
 
     Ok(())
 }
 
-pub async fn run(config: ClientConfig) -> Result<(), Error> {
-    write_config_to_file(config).await?;
+pub async fn run(config: ClientConfig, strict_config: bool) -> Result<(), Error> {
+    write_config_to_file(config, strict_config, false).await?;
 
     let status = Command::new("/app/frpc")
         .stdin(Stdio::null())
-        .args(&["-c", ROOT_CONFIG_PATH])
+        .args(["-c", ROOT_CONFIG_PATH])
+        .arg(format!("--strict_config={strict_config}"))
         .spawn()
         .map_err(|err| anyhow!("failed to spawn frpc: {err}"))?
         .wait()
@@ -85,19 +212,37 @@ pub async fn run(config: ClientConfig) -> Result<(), Error> {
     Ok(())
 }
 
-pub async fn reload() -> Result<(), Error> {
-    let status = Command::new("/app/frpc")
-        .stdin(Stdio::null())
-        .args(&["reload", "-c", ROOT_CONFIG_PATH])
-        .spawn()
-        .map_err(|err| anyhow!("failed to spawn frpc: {err}"))?
-        .wait()
-        .await
-        .map_err(|err| anyhow!("frpc output error: {err}"))?;
+/// Applies the proxy files through the frpc admin api, pushing them first
+/// when frpc is unmanaged.
+pub async fn reload(options: &Options) -> Result<(), Error> {
+    if options.unmanaged {
+        return admin::push(options.strict_config).await;
+    }
 
-    if !status.success() {
-        return Err(anyhow!("frpc reload exit with status: {status:?}").into());
+    admin::reload(options.strict_config).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unbracket_strips_ipv6_brackets_only() {
+        assert_eq!(unbracket("203.0.113.10"), "203.0.113.10");
+        assert_eq!(unbracket("[2001:db8::1]"), "2001:db8::1");
+        assert_eq!(unbracket("2001:db8::1"), "2001:db8::1");
+        assert_eq!(unbracket("frps.example.com"), "frps.example.com");
+        assert_eq!(unbracket("[2001:db8::1"), "[2001:db8::1");
     }
 
-    Ok(())
+    #[test]
+    fn join_host_port_brackets_ipv6_literals() {
+        assert_eq!(join_host_port("203.0.113.10", 7000), "203.0.113.10:7000");
+        assert_eq!(join_host_port("2001:db8::1", 7000), "[2001:db8::1]:7000");
+        assert_eq!(join_host_port("[2001:db8::1]", 7000), "[2001:db8::1]:7000");
+        assert_eq!(
+            join_host_port("web.default.svc.cluster.local", 80),
+            "web.default.svc.cluster.local:80"
+        );
+    }
 }
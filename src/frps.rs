@@ -0,0 +1,63 @@
+use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
+
+use crate::error::Error;
+
+#[derive(Debug, Clone)]
+pub struct Dashboard {
+    pub url: String,
+    pub user: Option<String>,
+    pub password: Option<String>,
+    client: reqwest::Client,
+}
+
+#[derive(Default, Debug, Clone, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ProxyStats {
+    pub name: String,
+    #[serde(default)]
+    pub status: String,
+    #[serde(default)]
+    pub today_traffic_in: u64,
+    #[serde(default)]
+    pub today_traffic_out: u64,
+    #[serde(default)]
+    pub cur_conns: u64,
+}
+
+#[derive(Default, Debug, Clone, Deserialize)]
+struct ProxyStatsResponse {
+    #[serde(default)]
+    proxies: Vec<ProxyStats>,
+}
+
+impl Dashboard {
+    pub fn new(url: String, user: Option<String>, password: Option<String>) -> Self {
+        Self {
+            url: url.trim_end_matches('/').to_string(),
+            user,
+            password,
+            client: reqwest::Client::new(),
+        }
+    }
+
+    pub async fn proxies(&self, type_: &str) -> Result<Vec<ProxyStats>, Error> {
+        let url = format!("{}/api/proxy/{type_}", self.url);
+
+        let mut req = self.client.get(&url);
+        if let Some(user) = self.user.as_ref() {
+            req = req.basic_auth(user, self.password.as_ref());
+        }
+
+        let res = req
+            .send()
+            .await
+            .and_then(|res| res.error_for_status())
+            .map_err(|err| anyhow!("failed to query frps dashboard {url}: {err}"))?
+            .json::<ProxyStatsResponse>()
+            .await
+            .map_err(|err| anyhow!("failed to decode frps dashboard response: {err}"))?;
+
+        Ok(res.proxies)
+    }
+}
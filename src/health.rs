@@ -0,0 +1,63 @@
+use std::{convert::Infallible, net::SocketAddr};
+
+use http_body_util::Full;
+use hyper::{
+    body::{Bytes, Incoming},
+    server::conn::http1,
+    service::service_fn,
+    Method, Request, Response, StatusCode,
+};
+use hyper_util::rt::TokioIo;
+use log::{info, warn};
+use tokio::net::TcpListener;
+
+use crate::{error::Error, frpc::admin, metrics::METRICS};
+
+async fn handle(
+    req: Request<Incoming>,
+    unmanaged: bool,
+) -> Result<Response<Full<Bytes>>, Infallible> {
+    let reply = |status: StatusCode, body: String| {
+        let mut res = Response::new(Full::new(Bytes::from(body)));
+        *res.status_mut() = status;
+        Ok(res)
+    };
+
+    // both probes go through the frpc admin api, a wedged frpc fails them. An
+    // unmanaged frpc runs elsewhere, restarting the operator can't fix it, so
+    // only readiness depends on it
+    let check = match (req.method(), req.uri().path()) {
+        (&Method::GET, "/healthz") if unmanaged => Ok(()),
+        (&Method::GET, "/healthz") => admin::healthz().await,
+        (&Method::GET, "/readyz") => admin::status().await.map(|_| ()),
+        (&Method::GET, "/metrics") => return reply(StatusCode::OK, METRICS.render()),
+        _ => return reply(StatusCode::NOT_FOUND, String::new()),
+    };
+
+    match check {
+        Ok(()) => reply(StatusCode::OK, "ok".to_string()),
+        Err(err) => reply(StatusCode::SERVICE_UNAVAILABLE, err.to_string()),
+    }
+}
+
+pub async fn run(addr: SocketAddr, unmanaged: bool) -> Result<(), Error> {
+    let listener = TcpListener::bind(addr).await?;
+
+    info!("health probes listening on {addr}");
+
+    loop {
+        let (stream, peer) = listener.accept().await?;
+
+        tokio::spawn(async move {
+            if let Err(err) = http1::Builder::new()
+                .serve_connection(
+                    TokioIo::new(stream),
+                    service_fn(|req| handle(req, unmanaged)),
+                )
+                .await
+            {
+                warn!("health probe connection with {peer} failed: {err}");
+            }
+        });
+    }
+}
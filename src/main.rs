@@ -1,29 +1,183 @@
+mod annotations;
 mod context;
 mod controllers;
 mod error;
 mod frpc;
+mod frps;
+mod health;
+mod metrics;
+mod webhook;
 
+use std::{collections::BTreeMap, time::Duration};
+
+use anyhow::anyhow;
 use clap::Parser;
-use log::info;
+use log::{error, info};
+use ring::rand::{SecureRandom, SystemRandom};
 
-use frpc::config::{Auth, ClientConfig, WebServer};
+use context::{LabelRequirement, Options};
+use frpc::config::{Auth, ClientConfig, LoadBalancer, Log, OidcAuth, WebServer};
+use frps::Dashboard;
+use webhook::WebhookOptions;
 
-use crate::frpc::config::Transport;
+use crate::frpc::config::{QuicOptions, TlsConfig, Transport};
 
 pub const OPERATOR_MANAGER: &str = "frp-operator";
 
 #[derive(Parser, Debug)]
 struct Args {
-    #[arg(short, long)]
-    server_addr: String,
-    #[arg(short, long)]
-    server_port: u16,
+    #[arg(
+        short,
+        long,
+        value_delimiter = ',',
+        required_unless_present = "unmanaged"
+    )]
+    server_addr: Vec<String>,
+    #[arg(short, long, required_unless_present = "unmanaged")]
+    server_port: Option<u16>,
     #[arg(short, long, default_value = "127.0.0.1")]
     webserver_addr: String,
     #[arg(short, long, default_value_t = 7400_u16)]
     webserver_port: u16,
+    #[arg(long, env)]
+    webserver_user: Option<String>,
+    #[arg(long, env)]
+    webserver_password: Option<String>,
+    #[arg(long, env, requires = "webserver_tls_key_file")]
+    webserver_tls_cert_file: Option<String>,
+    #[arg(long, env, requires = "webserver_tls_cert_file")]
+    webserver_tls_key_file: Option<String>,
+    #[arg(long, env, requires = "unmanaged")]
+    webserver_tls_trusted_ca_file: Option<String>,
     #[arg(short, long, env)]
     auth_token: Option<String>,
+    #[arg(long, env, default_value = "token", value_parser = ["token", "oidc"])]
+    auth_method: String,
+    #[arg(long, env, required_if_eq("auth_method", "oidc"))]
+    oidc_client_id: Option<String>,
+    #[arg(long, env)]
+    oidc_client_secret: Option<String>,
+    #[arg(long, env)]
+    oidc_audience: Option<String>,
+    #[arg(long, env)]
+    oidc_scope: Option<String>,
+    #[arg(long, env, required_if_eq("auth_method", "oidc"))]
+    oidc_token_endpoint_url: Option<String>,
+    #[arg(long, env, default_value = "quic", value_parser = ["tcp", "kcp", "quic", "websocket", "wss"])]
+    transport_protocol: String,
+    #[arg(long, env)]
+    pool_count: Option<u32>,
+    #[arg(long, env, allow_negative_numbers = true)]
+    heartbeat_interval: Option<i64>,
+    #[arg(long, env)]
+    heartbeat_timeout: Option<i64>,
+    #[arg(long, env)]
+    dial_server_timeout: Option<i64>,
+    #[arg(long, env, allow_negative_numbers = true)]
+    dial_server_keepalive: Option<i64>,
+    #[arg(long, env)]
+    tcp_mux: Option<bool>,
+    #[arg(long, env)]
+    tcp_mux_keepalive_interval: Option<i64>,
+    #[arg(long, env)]
+    proxy_url: Option<String>,
+    #[arg(long, env)]
+    quic_keepalive_period: Option<u64>,
+    #[arg(long, env)]
+    quic_max_idle_timeout: Option<u64>,
+    #[arg(long, env)]
+    quic_max_incoming_streams: Option<u64>,
+    #[arg(long, env)]
+    tls_enable: Option<bool>,
+    #[arg(long, env, requires = "tls_key_file")]
+    tls_cert_file: Option<String>,
+    #[arg(long, env, requires = "tls_cert_file")]
+    tls_key_file: Option<String>,
+    #[arg(long, env)]
+    tls_trusted_ca_file: Option<String>,
+    #[arg(long, env)]
+    tls_server_name: Option<String>,
+    #[arg(long, env)]
+    dns_server: Option<String>,
+    #[arg(long, env)]
+    login_fail_exit: Option<bool>,
+    #[arg(long, env, value_delimiter = ',')]
+    start: Vec<String>,
+    #[arg(long, env)]
+    udp_packet_size: Option<u32>,
+    #[arg(long, env)]
+    nat_hole_stun_server: Option<String>,
+    #[arg(long, env, value_delimiter = ',', value_parser = parse_key_value)]
+    client_metadatas: Vec<(String, String)>,
+    #[arg(long, env, value_parser = ["trace", "debug", "info", "warn", "error"])]
+    frpc_log_level: Option<String>,
+    #[arg(long, env)]
+    frpc_log_max_days: Option<u32>,
+    #[arg(long, env)]
+    frpc_log_to: Option<String>,
+    #[arg(long, env)]
+    extra_config: Option<String>,
+    #[arg(long, env, default_value_t = true, action = clap::ArgAction::Set)]
+    strict_config: bool,
+    #[arg(long, env, default_value_t = false)]
+    unmanaged: bool,
+    #[arg(long, env, requires = "default_lb_group_key")]
+    default_lb_group: Option<String>,
+    #[arg(long, env, requires = "default_lb_group")]
+    default_lb_group_key: Option<String>,
+    #[arg(long, env)]
+    group_key_secret: Option<String>,
+    #[arg(long, env, default_value_t = false)]
+    consolidate_proxies: bool,
+    #[arg(long, env, default_value_t = 80_u16)]
+    vhost_http_port: u16,
+    #[arg(long, env, default_value_t = 443_u16)]
+    vhost_https_port: u16,
+    #[arg(long, env)]
+    default_domain: Option<String>,
+    #[arg(long, env, value_delimiter = ',')]
+    metadata_labels: Vec<String>,
+    #[arg(long, env, value_delimiter = ',')]
+    metadata_annotations: Vec<String>,
+    #[arg(long, env)]
+    server_dashboard_url: Option<String>,
+    #[arg(long, env)]
+    server_dashboard_user: Option<String>,
+    #[arg(long, env)]
+    server_dashboard_password: Option<String>,
+    #[arg(long, env, default_value_t = 60_u64, value_parser = clap::value_parser!(u64).range(1..))]
+    stats_interval: u64,
+    #[arg(long, env, default_value_t = 1_u64)]
+    reconcile_debounce: u64,
+    #[arg(long, env, value_delimiter = ',')]
+    watch_namespaces: Vec<String>,
+    #[arg(long, env, value_delimiter = ',')]
+    exclude_namespaces: Vec<String>,
+    #[arg(long, env, value_delimiter = ',')]
+    ingress_selector: Vec<LabelRequirement>,
+    #[arg(long, env, value_delimiter = ',')]
+    service_selector: Vec<LabelRequirement>,
+    #[arg(long, env, value_delimiter = ',')]
+    namespace_selector: Vec<LabelRequirement>,
+    #[arg(long, env)]
+    pod_name: Option<String>,
+    #[arg(long, env)]
+    pod_namespace: Option<String>,
+    #[arg(long, env)]
+    health_addr: Option<std::net::SocketAddr>,
+    #[arg(long, env, default_value = "0.0.0.0:8443")]
+    webhook_addr: std::net::SocketAddr,
+    #[arg(long, env, requires = "webhook_key")]
+    webhook_cert: Option<String>,
+    #[arg(long, env, requires = "webhook_cert")]
+    webhook_key: Option<String>,
+}
+
+fn parse_key_value(s: &str) -> Result<(String, String), String> {
+    s.split_once('=')
+        .filter(|(key, _)| !key.is_empty())
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .ok_or_else(|| format!("expected key=value, got {s}"))
 }
 
 #[tokio::main]
@@ -32,27 +186,167 @@ async fn main() -> anyhow::Result<()> {
 
     info!("starting frp operator");
 
-    let args = Args::parse();
+    let mut args = Args::parse();
+    for server_addr in args.server_addr.iter_mut() {
+        *server_addr = frpc::unbracket(server_addr).to_string();
+    }
 
     let cfg = ClientConfig {
-        server_addr: args.server_addr,
-        server_port: args.server_port,
+        server_addr: args.server_addr.first().cloned().unwrap_or_default(),
+        server_port: args.server_port.unwrap_or_default(),
         webserver: Some(WebServer {
-            addr: Some(args.webserver_addr),
+            addr: Some(frpc::unbracket(&args.webserver_addr).to_string()),
             port: args.webserver_port,
+            user: args.webserver_user,
+            password: args.webserver_password,
+            // an unmanaged frpc's certificate is verified against the trusted ca
+            tls: Some(TlsConfig {
+                cert_file: args.webserver_tls_cert_file,
+                key_file: args.webserver_tls_key_file,
+                trusted_ca_file: args.webserver_tls_trusted_ca_file,
+                ..TlsConfig::default()
+            })
+            .filter(|tls| *tls != TlsConfig::default()),
+            ..WebServer::default()
         }),
-        auth: args.auth_token.map(|token| Auth {
-            method: "token".to_string(),
-            token: Some(token),
-        }),
+        auth: match args.auth_method.as_str() {
+            "oidc" => Some(Auth {
+                method: args.auth_method,
+                oidc: args.oidc_client_id.zip(args.oidc_token_endpoint_url).map(
+                    |(client_id, token_endpoint_url)| OidcAuth {
+                        client_id,
+                        client_secret: args.oidc_client_secret,
+                        audience: args.oidc_audience,
+                        scope: args.oidc_scope,
+                        token_endpoint_url,
+                    },
+                ),
+                ..Auth::default()
+            }),
+            _ => args.auth_token.map(|token| Auth {
+                method: args.auth_method,
+                token: Some(token),
+                ..Auth::default()
+            }),
+        },
         includes: vec!["/etc/frp/proxy-*.toml".to_string()],
         transport: Some(Transport {
-            protocol: Some("quic".to_string()),
+            protocol: Some(args.transport_protocol),
+            pool_count: args.pool_count,
+            heartbeat_interval: args.heartbeat_interval,
+            heartbeat_timeout: args.heartbeat_timeout,
+            dial_server_timeout: args.dial_server_timeout,
+            dial_server_keepalive: args.dial_server_keepalive,
+            tcp_mux: args.tcp_mux,
+            tcp_mux_keepalive_interval: args.tcp_mux_keepalive_interval,
+            proxy_url: args.proxy_url,
+            quic: Some(QuicOptions {
+                keepalive_period: args.quic_keepalive_period,
+                max_idle_timeout: args.quic_max_idle_timeout,
+                max_incoming_streams: args.quic_max_incoming_streams,
+            })
+            .filter(|quic| *quic != QuicOptions::default()),
+            tls: Some(TlsConfig {
+                enable: args.tls_enable,
+                cert_file: args.tls_cert_file,
+                key_file: args.tls_key_file,
+                trusted_ca_file: args.tls_trusted_ca_file,
+                server_name: args.tls_server_name,
+                ..TlsConfig::default()
+            })
+            .filter(|tls| *tls != TlsConfig::default()),
+            ..Transport::default()
         }),
+        dns_server: args.dns_server,
+        login_fail_exit: args.login_fail_exit,
+        start: args.start,
+        udp_packet_size: args.udp_packet_size,
+        nat_hole_stun_server: args.nat_hole_stun_server,
+        metadatas: Some(args.client_metadatas.into_iter().collect())
+            .filter(|metadatas: &BTreeMap<String, String>| !metadatas.is_empty()),
+        log: Some(Log {
+            to: args.frpc_log_to,
+            level: args.frpc_log_level,
+            max_days: args.frpc_log_max_days,
+            ..Log::default()
+        })
+        .filter(|log| *log != Log::default()),
         ..ClientConfig::default()
     };
 
-    controllers::run(cfg).await?;
+    let cfg = match args.extra_config {
+        Some(extra) => cfg.merge(
+            toml::from_str(&extra).map_err(|err| anyhow!("failed to parse extra config: {err}"))?,
+        )?,
+        None => cfg,
+    };
+
+    let options = Options {
+        consolidate_proxies: args.consolidate_proxies,
+        vhost_http_port: args.vhost_http_port,
+        vhost_https_port: args.vhost_https_port,
+        default_domain: args.default_domain,
+        metadata_labels: args.metadata_labels,
+        metadata_annotations: args.metadata_annotations,
+        dashboard: args.server_dashboard_url.map(|url| {
+            Dashboard::new(
+                url,
+                args.server_dashboard_user,
+                args.server_dashboard_password,
+            )
+        }),
+        stats_interval: Duration::from_secs(args.stats_interval),
+        reconcile_debounce: Duration::from_secs(args.reconcile_debounce),
+        watch_namespaces: args.watch_namespaces,
+        exclude_namespaces: args.exclude_namespaces,
+        ingress_selector: args.ingress_selector,
+        service_selector: args.service_selector,
+        namespace_selector: args.namespace_selector,
+        strict_config: args.strict_config,
+        unmanaged: args.unmanaged,
+        default_lb_group: args
+            .default_lb_group
+            .zip(args.default_lb_group_key)
+            .map(|(group, group_key)| LoadBalancer { group, group_key }),
+        server_addrs: args.server_addr,
+        pod_name: args.pod_name,
+        pod_namespace: args.pod_namespace,
+        group_key_secret: match args.group_key_secret {
+            Some(secret) => secret.into_bytes(),
+            None => {
+                // keys only have to agree within this process when no secret is shared
+                let mut secret = vec![0; 32];
+                SystemRandom::new()
+                    .fill(&mut secret)
+                    .map_err(|_| anyhow!("failed to generate group key secret"))?;
+                secret
+            }
+        },
+    };
+
+    if let Some(addr) = args.health_addr {
+        let unmanaged = options.unmanaged;
+        tokio::spawn(async move {
+            if let Err(err) = health::run(addr, unmanaged).await {
+                error!("health probe server failed: {err}");
+            }
+        });
+    }
+
+    if let Some((cert_path, key_path)) = args.webhook_cert.zip(args.webhook_key) {
+        let webhook_options = WebhookOptions {
+            addr: args.webhook_addr,
+            cert_path,
+            key_path,
+        };
+        tokio::spawn(async move {
+            if let Err(err) = webhook::run(webhook_options).await {
+                error!("webhook server failed: {err}");
+            }
+        });
+    }
+
+    controllers::run(cfg, options).await?;
 
     Ok(())
 }
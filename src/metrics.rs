@@ -0,0 +1,65 @@
+use std::sync::LazyLock;
+
+use prometheus::{IntCounterVec, IntGaugeVec, Opts, Registry, TextEncoder};
+
+const PROXY_LABELS: [&str; 4] = ["kind", "namespace", "name", "proxy"];
+
+pub struct Metrics {
+    registry: Registry,
+    pub proxy_connections: IntGaugeVec,
+    pub proxy_traffic_in: IntCounterVec,
+    pub proxy_traffic_out: IntCounterVec,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let registry = Registry::new_custom(Some("frp_operator".to_string()), None)
+            .expect("valid metrics prefix");
+
+        let proxy_connections = IntGaugeVec::new(
+            Opts::new("proxy_connections", "Current connections of a proxy"),
+            &PROXY_LABELS,
+        )
+        .expect("valid metric");
+        let proxy_traffic_in = IntCounterVec::new(
+            Opts::new(
+                "proxy_traffic_in_bytes_total",
+                "Bytes frps received for a proxy since the operator started",
+            ),
+            &PROXY_LABELS,
+        )
+        .expect("valid metric");
+        let proxy_traffic_out = IntCounterVec::new(
+            Opts::new(
+                "proxy_traffic_out_bytes_total",
+                "Bytes frps sent for a proxy since the operator started",
+            ),
+            &PROXY_LABELS,
+        )
+        .expect("valid metric");
+
+        for collector in [
+            Box::new(proxy_connections.clone()) as Box<dyn prometheus::core::Collector>,
+            Box::new(proxy_traffic_in.clone()),
+            Box::new(proxy_traffic_out.clone()),
+        ] {
+            registry.register(collector).expect("unique metric");
+        }
+
+        Self {
+            registry,
+            proxy_connections,
+            proxy_traffic_in,
+            proxy_traffic_out,
+        }
+    }
+
+    /// Renders every metric in the Prometheus text format.
+    pub fn render(&self) -> String {
+        TextEncoder::new()
+            .encode_to_string(&self.registry.gather())
+            .unwrap_or_default()
+    }
+}
+
+pub static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
@@ -0,0 +1,200 @@
+use std::{collections::BTreeMap, convert::Infallible, io::BufReader, net::SocketAddr, sync::Arc};
+
+use anyhow::anyhow;
+use http_body_util::{BodyExt, Full};
+use hyper::{
+    body::{Bytes, Incoming},
+    server::conn::http1,
+    service::service_fn,
+    Method, Request, Response, StatusCode,
+};
+use hyper_util::rt::TokioIo;
+use k8s_openapi::api::{core::v1::Service, networking::v1::Ingress};
+use kube::{
+    core::admission::{AdmissionRequest, AdmissionResponse, AdmissionReview},
+    Resource, ResourceExt,
+};
+use log::{info, warn};
+use serde::de::DeserializeOwned;
+use tokio::{fs, net::TcpListener};
+use tokio_rustls::{rustls::ServerConfig, TlsAcceptor};
+
+use crate::{
+    annotations,
+    controllers::{ingress, service},
+    error::Error,
+};
+
+#[derive(Debug, Clone)]
+pub struct WebhookOptions {
+    pub addr: SocketAddr,
+    pub cert_path: String,
+    pub key_path: String,
+}
+
+async fn tls_acceptor(options: &WebhookOptions) -> Result<TlsAcceptor, Error> {
+    let cert = fs::read(&options.cert_path)
+        .await
+        .map_err(|err| anyhow!("failed to read cert {}: {err}", options.cert_path))?;
+    let key = fs::read(&options.key_path)
+        .await
+        .map_err(|err| anyhow!("failed to read key {}: {err}", options.key_path))?;
+
+    let certs = rustls_pemfile::certs(&mut BufReader::new(cert.as_slice()))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|err| anyhow!("failed to parse cert {}: {err}", options.cert_path))?;
+    let key = rustls_pemfile::private_key(&mut BufReader::new(key.as_slice()))
+        .map_err(|err| anyhow!("failed to parse key {}: {err}", options.key_path))?
+        .ok_or_else(|| anyhow!("no private key found in {}", options.key_path))?;
+
+    let config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|err| anyhow!("invalid webhook certificate: {err}"))?;
+
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// frp-operator.io annotations, leaving out the ones the operator writes itself.
+fn frp_annotations<K: ResourceExt>(obj: &K) -> BTreeMap<&String, &String> {
+    obj.annotations()
+        .iter()
+        .filter(|(key, _)| {
+            key.starts_with(annotations::PREFIX)
+                && !annotations::OPERATOR_OWNED.contains(&key.as_str())
+        })
+        .collect()
+}
+
+/// Validates frp objects, except ones being deleted so they can still drop
+/// their finalizer, and updates that leave the frp annotations and the spec
+/// alone so objects admitted before aren't stuck on unrelated changes.
+fn validate<K>(
+    req: &AdmissionRequest<K>,
+    is_frp: fn(&K) -> bool,
+    validate: fn(&K) -> Result<(), Error>,
+    spec: fn(&K) -> Option<serde_json::Value>,
+) -> AdmissionResponse
+where
+    K: Resource + ResourceExt,
+{
+    let res = AdmissionResponse::from(req);
+
+    let Some(obj) = req.object.as_ref().filter(|obj| is_frp(obj)) else {
+        return res;
+    };
+
+    if obj.meta().deletion_timestamp.is_some() {
+        return res;
+    }
+
+    if req.old_object.as_ref().is_some_and(|old| {
+        is_frp(old) && frp_annotations(old) == frp_annotations(obj) && spec(old) == spec(obj)
+    }) {
+        return res;
+    }
+
+    match validate(obj) {
+        Ok(()) => res,
+        Err(err) => res.deny(err.to_string()),
+    }
+}
+
+fn validate_ingress(req: &AdmissionRequest<Ingress>) -> AdmissionResponse {
+    validate(
+        req,
+        ingress::is_frp_ingress,
+        ingress::validate_ingress,
+        |ing| serde_json::to_value(&ing.spec).ok(),
+    )
+}
+
+fn validate_service(req: &AdmissionRequest<Service>) -> AdmissionResponse {
+    validate(
+        req,
+        service::is_frp_service,
+        service::validate_service,
+        |svc| serde_json::to_value(&svc.spec).ok(),
+    )
+}
+
+/// Decodes an admission review of `K` and encodes the response of `validate` to it.
+fn review<K>(
+    body: &[u8],
+    validate: fn(&AdmissionRequest<K>) -> AdmissionResponse,
+) -> Result<Vec<u8>, (StatusCode, String)>
+where
+    K: Resource + DeserializeOwned,
+{
+    let review: AdmissionReview<K> =
+        serde_json::from_slice(body).map_err(|err| (StatusCode::BAD_REQUEST, err.to_string()))?;
+
+    let res = match review.try_into() {
+        Ok(req) => validate(&req),
+        Err(err) => AdmissionResponse::invalid(err.to_string()),
+    };
+
+    serde_json::to_vec(&res.into_review())
+        .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))
+}
+
+async fn handle(req: Request<Incoming>) -> Result<Response<Full<Bytes>>, Infallible> {
+    let reply = |status: StatusCode, body: Vec<u8>| {
+        let mut res = Response::new(Full::new(Bytes::from(body)));
+        *res.status_mut() = status;
+        Ok(res)
+    };
+
+    if req.method() != Method::POST {
+        return reply(StatusCode::NOT_FOUND, vec![]);
+    }
+
+    let path = req.uri().path().to_string();
+    if !matches!(path.as_str(), "/validate-ingress" | "/validate-service") {
+        return reply(StatusCode::NOT_FOUND, vec![]);
+    }
+
+    let body = match req.into_body().collect().await {
+        Ok(body) => body.to_bytes(),
+        Err(err) => return reply(StatusCode::BAD_REQUEST, err.to_string().into_bytes()),
+    };
+
+    let res = match path.as_str() {
+        "/validate-ingress" => review(&body, validate_ingress),
+        _ => review(&body, validate_service),
+    };
+
+    match res {
+        Ok(body) => reply(StatusCode::OK, body),
+        Err((status, err)) => reply(status, err.into_bytes()),
+    }
+}
+
+pub async fn run(options: WebhookOptions) -> Result<(), Error> {
+    let acceptor = tls_acceptor(&options).await?;
+    let listener = TcpListener::bind(options.addr).await?;
+
+    info!("webhook listening on {}", options.addr);
+
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        let acceptor = acceptor.clone();
+
+        tokio::spawn(async move {
+            let stream = match acceptor.accept(stream).await {
+                Ok(stream) => stream,
+                Err(err) => {
+                    warn!("webhook tls handshake with {peer} failed: {err}");
+                    return;
+                }
+            };
+
+            if let Err(err) = http1::Builder::new()
+                .serve_connection(TokioIo::new(stream), service_fn(handle))
+                .await
+            {
+                warn!("webhook connection with {peer} failed: {err}");
+            }
+        });
+    }
+}